@@ -24,50 +24,145 @@ class Bitstream:
             self.buffer.append(0)
             self.shift = 0
 
-fps = 65.5
+    def position(self) -> int:
+        return (len(self.buffer) - 1) * 8 + self.shift
+
+# Ticks per second of the cart's playback clock, shared with the video decoder.
+tick_rate = int(environ.get("TICK_RATE", "60"))
+# Ticks to delay the music by relative to the video; may be negative.
+av_offset = int(environ.get("AV_OFFSET", "0"))
+# Whether the music jumps back to its loop point instead of ending.
+loop_music = environ.get("LOOP_MUSIC", "0") == "1"
+# music.mid is sequenced at 150 BPM, slightly faster than the video's audio.
+tempo_scale = 65.5 / 60
+# Loop points in seconds of music.mid. When not set here, they are taken from
+# "loopStart" and "loopEnd" text events, or else the start and end of the song.
+loop_start = None
+loop_end = None
+
+# Flag bits understood by WASM-4's tone(), see src/wasm4.rs.
+TONE_MODES = [0, 4, 8, 12]
+TONE_PAN_LEFT = 16
+TONE_PAN_RIGHT = 32
+
+PAN_CC = 10
+DUTY_CC = 12
+
+def controller_at(instrument, number: int, time: float):
+    value = None
+    for cc in instrument.control_changes:
+        if cc.time > time:
+            break
+        if cc.number == number:
+            value = cc.value
+    return value
+
+def note_mode(instrument, name: str, time: float) -> int:
+    mode = 0
+    pan = controller_at(instrument, PAN_CC, time)
+    if pan is not None and pan < 43:
+        mode |= TONE_PAN_LEFT
+    if pan is not None and pan > 84:
+        mode |= TONE_PAN_RIGHT
+    duty = controller_at(instrument, DUTY_CC, time)
+    if duty is not None and name.startswith("pulse"):
+        mode |= TONE_MODES[duty // 32]
+    return mode
+
+def to_ticks(time: float) -> int:
+    return round(time * tempo_scale * tick_rate) + av_offset
+
+def text_event_time(text: str):
+    for event in midi_data.text_events:
+        if event.text.strip() == text:
+            return event.time
+    return None
+
 channels = ["pulse_two", "triangle", "pulse_one", "noise"]
 midi_data = pretty_midi.PrettyMIDI("music.mid")
+if loop_start is None:
+    loop_start = text_event_time("loopStart") or 0
+if loop_end is None:
+    loop_end = text_event_time("loopEnd") or midi_data.get_end_time()
+loop_start = max(to_ticks(loop_start), 0)
+loop_end = to_ticks(loop_end)
+print("loop", loop_start, loop_end)
+
+tables = open(f"{environ['OUT_DIR']}/music.rs", "w")
+tables.write(f"const LOOPING: bool = {str(loop_music).lower()};\n")
 for instrument, name in zip(midi_data.instruments, channels):
     deltas = set()
     lengths = set()
     pitches = set()
+    modes = set()
     notes = []
     prev_time = 0
+    loop_note = None
+    loop_delta = 0
     for note in instrument.notes:
-        time = round(note.start * fps)
+        time = to_ticks(note.start)
+        if time < 0 or time >= loop_end:
+            continue
+        if loop_note is None and time >= loop_start:
+            loop_note = len(notes)
+            loop_delta = time - loop_start
         delta = time - prev_time
-        length = round((note.end - note.start) * fps)
+        # tone() has 8 bits for the sustain time.
+        length = min(round((note.end - note.start) * tempo_scale * tick_rate), 255)
         pitch = round(pretty_midi.note_number_to_hz(note.pitch))
+        mode = note_mode(instrument, name, note.start)
         deltas.add(delta)
         lengths.add(length)
         pitches.add(pitch)
-        notes.append((delta, length, pitch))
+        modes.add(mode)
+        notes.append((delta, length, pitch, mode))
         prev_time = time
+    if loop_note is None:
+        loop_note = len(notes)
+    loop_delta += loop_end - prev_time
     deltas = sorted(deltas)
     lengths = sorted(lengths)
     pitches = sorted(pitches)
+    modes = sorted(modes)
+    delta_bits = (len(deltas) - 1).bit_length()
+    length_bits = (len(lengths) - 1).bit_length()
+    pitch_bits = (len(pitches) - 1).bit_length()
+    mode_bits = (len(modes) - 1).bit_length()
     print(name)
-    print(deltas)
-    print(lengths)
-    print(pitches)
+    print(delta_bits, deltas)
+    print(length_bits, lengths)
+    print(pitch_bits, pitches)
+    print(mode_bits, modes)
     stream = Bitstream()
-    for delta, length, pitch in notes:
-        delta = deltas.index(delta)
-        length = lengths.index(length)
-        pitch = pitches.index(pitch)
-        if name == "pulse_two":
-            stream.write(delta, 3)
-        if name == "triangle":
-            stream.write(delta, 4)
-            stream.write(length, 3)
-            stream.write(pitch, 5)
-        if name == "pulse_one":
-            stream.write(delta, 3)
-            stream.write(length, 2)
-            stream.write(pitch, 5)
-        if name == "noise":
-            stream.write(delta, 4)
-            stream.write(length, 1)
+    for i, (delta, length, pitch, mode) in enumerate(notes):
+        if i == loop_note:
+            loop_bit = stream.position()
+        stream.write(deltas.index(delta), delta_bits)
+        stream.write(lengths.index(length), length_bits)
+        stream.write(pitches.index(pitch), pitch_bits)
+        stream.write(modes.index(mode), mode_bits)
+    if loop_note == len(notes):
+        loop_bit = stream.position()
 
     with open(f"{environ['OUT_DIR']}/{name}.bin", "wb+") as file:
         file.write(bytes(stream.buffer))
+
+    tables.write(f"""const {name.upper()}_DATA: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/{name}.bin"));
+const {name.upper()}: ChannelReader = ChannelReader {{
+    data: {name.upper()}_DATA,
+    stream: BitStream::new({name.upper()}_DATA),
+    index: 0,
+    notes: {len(notes)},
+    loop_note: {loop_note},
+    loop_bit: {loop_bit},
+    loop_delta: {loop_delta},
+    delta_bits: {delta_bits},
+    deltas: &{deltas},
+    length_bits: {length_bits},
+    lengths: &{lengths},
+    pitch_bits: {pitch_bits},
+    pitches: &{pitches},
+    mode_bits: {mode_bits},
+    modes: &{modes},
+}};
+""")
@@ -0,0 +1,171 @@
+use crate::bitvec::{RangeEncoder, PROBABILITY_HALF};
+use crate::{EncodedFrame, Run, PALETTE};
+
+const KINDS: usize = PALETTE.len() + 1;
+const KIND_BITS: u32 = usize::BITS - (KINDS - 1).leading_zeros();
+/// Runs are shorter than 2^LENGTH_BITS pixels.
+const LENGTH_BITS: usize = 16;
+
+/// Adaptive probabilities for runs, mirroring `RunContexts` in `src/lib.rs`.
+///
+/// A run's kind is coded as a binary tree conditioned on the previous run's kind
+/// in the rect. Its length is coded as an Elias gamma code: a unary exponent and
+/// the leading mantissa bit are conditioned on the kind, the rest are direct bits.
+struct RunContexts {
+    kinds: [[u16; 1 << KIND_BITS]; KINDS + 1],
+    exponents: [[u16; LENGTH_BITS]; KINDS],
+    mantissas: [[u16; LENGTH_BITS]; KINDS],
+}
+
+impl RunContexts {
+    fn new() -> Self {
+        RunContexts {
+            kinds: [[PROBABILITY_HALF; 1 << KIND_BITS]; KINDS + 1],
+            exponents: [[PROBABILITY_HALF; LENGTH_BITS]; KINDS],
+            mantissas: [[PROBABILITY_HALF; LENGTH_BITS]; KINDS],
+        }
+    }
+
+    fn encode(&mut self, to: &mut RangeEncoder, run: Run, prev_kind: usize) {
+        let kind = run.kind as usize;
+        let tree = &mut self.kinds[prev_kind];
+        let mut node = 1;
+        for i in (0..KIND_BITS).rev() {
+            let bit = kind >> i & 1;
+            to.write_bit(&mut tree[node], bit != 0);
+            node = node * 2 + bit;
+        }
+
+        assert!(run.length < 1 << LENGTH_BITS, "run too long");
+        let exponent = 31 - run.length.leading_zeros() as usize;
+        for i in 0..=exponent {
+            to.write_bit(&mut self.exponents[kind][i], i < exponent);
+        }
+        if exponent > 0 {
+            let mantissa = run.length - (1 << exponent);
+            let top = exponent as u32 - 1;
+            to.write_bit(&mut self.mantissas[kind][exponent], mantissa >> top != 0);
+            to.write_direct(mantissa, top);
+        }
+    }
+}
+
+/// Range codes the runs of every rect, in the order the cart reads them.
+pub fn encode_runs(data: &[EncodedFrame]) -> Vec<u8> {
+    let mut encoder = RangeEncoder::new();
+    let mut contexts = RunContexts::new();
+    for rect in data.iter().flat_map(EncodedFrame::rects) {
+        let mut prev_kind = KINDS;
+        for &run in &rect.runs {
+            contexts.encode(&mut encoder, run, prev_kind);
+            prev_kind = run.kind as usize;
+        }
+    }
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitstream::RangeDecoder;
+    use crate::references::ReferenceCommand;
+    use crate::{EncodedRect, FrameCoding, Rect};
+
+    /// Reads a run the way `read_run` in the cart does.
+    fn decode_run(from: &mut RangeDecoder, contexts: &mut RunContexts, prev_kind: usize) -> Run {
+        let tree = &mut contexts.kinds[prev_kind];
+        let mut node = 1;
+        while node < 1 << KIND_BITS {
+            node = node * 2 + from.read_bit(&mut tree[node]) as usize;
+        }
+        let kind = node - (1 << KIND_BITS);
+        let mut exponent = 0;
+        while from.read_bit(&mut contexts.exponents[kind][exponent]) {
+            exponent += 1;
+        }
+        let mut length = 1;
+        if exponent > 0 {
+            let top = from.read_bit(&mut contexts.mantissas[kind][exponent]) as u32;
+            length = (2 | top) << (exponent - 1) | from.read_direct(exponent as u32 - 1);
+        }
+        Run {
+            length,
+            kind: kind as u8,
+        }
+    }
+
+    #[test]
+    fn bits_round_trip() {
+        // Mostly zeros, so the probabilities adapt far from a half, with direct bits
+        // and long stretches of ones to carry into bytes already held back.
+        let bits: Vec<_> = (0..5000u32)
+            .map(|i| {
+                (
+                    i % 97 == 0 || (3000..3300).contains(&i),
+                    i.wrapping_mul(2654435761),
+                )
+            })
+            .collect();
+        let mut encoder = RangeEncoder::new();
+        let mut probability = PROBABILITY_HALF;
+        for &(bit, direct) in &bits {
+            encoder.write_bit(&mut probability, bit);
+            encoder.write_direct(direct, 7);
+        }
+        let bytes = encoder.finish();
+
+        let mut decoder = RangeDecoder::new(&bytes);
+        let mut probability = PROBABILITY_HALF;
+        for &(bit, direct) in &bits {
+            assert_eq!(decoder.read_bit(&mut probability), bit);
+            assert_eq!(decoder.read_direct(7), direct & 0x7F);
+        }
+    }
+
+    #[test]
+    fn runs_round_trip() {
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            w: 1,
+            h: 1,
+        };
+        let rects: Vec<Vec<Run>> = (0..40u32)
+            .map(|i| {
+                (0..i % 7 + 1)
+                    .map(|j| Run {
+                        length: 1 + (i * 31 + j * 17) % 300 + (j == 3) as u32 * 60000,
+                        kind: ((i + j) % KINDS as u32) as u8,
+                    })
+                    .collect()
+            })
+            .collect();
+        let data = [EncodedFrame {
+            duration: 1,
+            reference: ReferenceCommand::default(),
+            coding: FrameCoding::Rects(
+                rects
+                    .iter()
+                    .map(|runs| EncodedRect {
+                        rect,
+                        order: 0,
+                        motion: None,
+                        xor: false,
+                        runs: runs.clone(),
+                    })
+                    .collect(),
+            ),
+        }];
+        let bytes = encode_runs(&data);
+
+        let mut decoder = RangeDecoder::new(&bytes);
+        let mut contexts = RunContexts::new();
+        for runs in &rects {
+            let mut prev_kind = KINDS;
+            for &run in runs {
+                assert_eq!(decode_run(&mut decoder, &mut contexts, prev_kind), run);
+                prev_kind = run.kind as usize;
+            }
+        }
+    }
+}
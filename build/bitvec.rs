@@ -54,12 +54,17 @@ impl BitVec {
         self.write_fibonacci(v);
     }
 
+    /// Writes a signed value, zigzagged so small magnitudes get short codes.
+    pub fn write_signed(&mut self, v: i32) {
+        self.write_int(((v << 1) ^ (v >> 31)) as u32 + 1);
+    }
+
     pub fn len(&self) -> usize {
         self.data.len()
     }
 
     pub fn bytes(&self) -> usize {
-        (self.data.len() + 7) / 8
+        self.data.len().div_ceil(8)
     }
 
     pub fn append(&mut self, other: &BitVec) {
@@ -78,3 +83,123 @@ impl BitVec {
         Ok(())
     }
 }
+
+/// Probabilities are fixed point with this many bits, and adapt by 1/32 of the
+/// distance to 0 or 1 after every bit. Must match `src/bitstream.rs`.
+pub const PROBABILITY_BITS: u32 = 11;
+pub const PROBABILITY_HALF: u16 = 1 << (PROBABILITY_BITS - 1);
+const ADAPT_SHIFT: u32 = 5;
+
+/// Adaptive binary range coder, read by the cart's `RangeDecoder`.
+pub struct RangeEncoder {
+    low: u64,
+    range: u32,
+    cache: u8,
+    pending: usize,
+    bytes: Vec<u8>,
+}
+
+impl RangeEncoder {
+    pub fn new() -> Self {
+        RangeEncoder {
+            low: 0,
+            range: u32::MAX,
+            cache: 0,
+            pending: 1,
+            bytes: vec![],
+        }
+    }
+
+    /// Writes out the top byte of `low`, holding back bytes that a carry may still change.
+    fn shift_low(&mut self) {
+        if self.low < 0xFF000000 || self.low >= 1 << 32 {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            for _ in 0..self.pending {
+                self.bytes.push(byte.wrapping_add(carry));
+                byte = 0xFF;
+            }
+            self.pending = 0;
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.pending += 1;
+        self.low = (self.low & 0x00FFFFFF) << 8;
+    }
+
+    fn normalize(&mut self) {
+        while self.range < 1 << 24 {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    /// Writes a bit whose chance of being 0 is `probability`, then adapts it.
+    pub fn write_bit(&mut self, probability: &mut u16, bit: bool) {
+        let bound = (self.range >> PROBABILITY_BITS) * *probability as u32;
+        if bit {
+            self.low += bound as u64;
+            self.range -= bound;
+            *probability -= *probability >> ADAPT_SHIFT;
+        } else {
+            self.range = bound;
+            *probability += ((1 << PROBABILITY_BITS) - *probability) >> ADAPT_SHIFT;
+        }
+        self.normalize();
+    }
+
+    /// Writes the low `count` bits of `v` as equally likely bits, most significant first.
+    pub fn write_direct(&mut self, v: u32, count: u32) {
+        for i in (0..count).rev() {
+            self.range >>= 1;
+            if v & 1 << i != 0 {
+                self.low += self.range as u64;
+            }
+            self.normalize();
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+        // The first byte is always zero, so the decoder doesn't need it.
+        assert_eq!(self.bytes.remove(0), 0);
+        self.bytes
+    }
+}
+
+/// Frequencies add up to 2^RANS_SCALE_BITS. Must match `src/bitstream.rs`.
+pub const RANS_SCALE_BITS: u32 = 12;
+const RANS_LOWER_BOUND: u32 = 1 << 23;
+
+/// Static rANS coder, read by the cart's `RansDecoder`. Symbols are written in the
+/// reverse of the order they are read.
+pub struct RansEncoder {
+    state: u32,
+    bytes: Vec<u8>,
+}
+
+impl RansEncoder {
+    pub fn new() -> Self {
+        RansEncoder {
+            state: RANS_LOWER_BOUND,
+            bytes: vec![],
+        }
+    }
+
+    /// Writes a symbol occupying `freq` slots from `start`.
+    pub fn write(&mut self, start: u32, freq: u32) {
+        let max_state = ((RANS_LOWER_BOUND >> RANS_SCALE_BITS) << 8) * freq;
+        while self.state >= max_state {
+            self.bytes.push(self.state as u8);
+            self.state >>= 8;
+        }
+        self.state = ((self.state / freq) << RANS_SCALE_BITS) + self.state % freq + start;
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.bytes.extend(self.state.to_be_bytes());
+        self.bytes.reverse();
+        self.bytes
+    }
+}
@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+
+use crate::bitvec::BitVec;
+use crate::huffman::HuffmanCode;
+use crate::{
+    EncodedFrame, FrameCoding, Run, MAX_CODE_LENGTH, PALETTE, RESCALE_HEIGHT, RESCALE_WIDTH,
+};
+
+const KINDS: usize = PALETTE.len() + 1;
+
+/// Splits a run length into a bucket, extra bits and their count, DEFLATE style: the
+/// first four buckets are single lengths, and each later pair of buckets covers a
+/// power of two.
+pub fn length_bucket(length: u32) -> (u8, u32, u32) {
+    let v = length - 1;
+    if v < 4 {
+        return (v as u8, 0, 0);
+    }
+    let exponent = 31 - v.leading_zeros();
+    let extra_bits = exponent - 1;
+    let bucket = 2 * exponent + (v >> extra_bits & 1);
+    (bucket as u8, v & ((1 << extra_bits) - 1), extra_bits)
+}
+
+/// Huffman codes for runs whose kind and length are coded separately: a code for
+/// the kind, then for each kind a code for the length bucket.
+pub struct SplitRunCode {
+    pub kinds: HuffmanCode<u8>,
+    pub buckets: Vec<Option<HuffmanCode<u8>>>,
+}
+
+impl SplitRunCode {
+    pub fn new(run_freq: &HashMap<Run, u64>) -> Self {
+        let mut kind_freq = HashMap::new();
+        let mut bucket_freq = vec![HashMap::new(); KINDS];
+        for (run, &count) in run_freq {
+            *kind_freq.entry(run.kind).or_default() += count;
+            let (bucket, _, _) = length_bucket(run.length);
+            *bucket_freq[run.kind as usize].entry(bucket).or_default() += count;
+        }
+        SplitRunCode {
+            kinds: HuffmanCode::new(kind_freq, MAX_CODE_LENGTH),
+            buckets: bucket_freq
+                .into_iter()
+                .map(|freq| match freq.is_empty() {
+                    true => None,
+                    false => Some(HuffmanCode::new(freq, MAX_CODE_LENGTH)),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn encode(&self, into: &mut BitVec, run: &Run) {
+        self.kinds.encode_value(into, &run.kind);
+        let (bucket, extra, extra_bits) = length_bucket(run.length);
+        self.buckets[run.kind as usize]
+            .as_ref()
+            .unwrap()
+            .encode_value(into, &bucket);
+        into.write_bits(extra, extra_bits);
+    }
+
+    pub fn code_length(&self, run: &Run) -> Option<usize> {
+        let (bucket, _, extra_bits) = length_bucket(run.length);
+        let buckets = self.buckets[run.kind as usize].as_ref()?;
+        Some(
+            self.kinds.code_length(&run.kind)?
+                + buckets.code_length(&bucket)?
+                + extra_bits as usize,
+        )
+    }
+
+    /// Number of bits the runs counted in `run_freq` take.
+    pub fn bits(&self, run_freq: &HashMap<Run, u64>) -> usize {
+        run_freq
+            .iter()
+            .map(|(run, &count)| count as usize * self.code_length(run).unwrap())
+            .sum()
+    }
+
+    /// Size of the tables in the cart: the codeword counts of every table, and the
+    /// values of each as bytes.
+    pub fn table_bytes(&self) -> usize {
+        let buckets: usize = self
+            .buckets
+            .iter()
+            .flatten()
+            .map(|code| code.values().len())
+            .sum();
+        (1 + KINDS) * MAX_CODE_LENGTH * 2 + self.kinds.values().len() + buckets
+    }
+}
+
+/// Frames between segment boundaries, unless a scene cut comes first.
+const SEGMENT_FRAMES: usize = 100;
+/// Shortest segment that a scene cut can end.
+const MIN_SEGMENT_FRAMES: usize = 10;
+/// Size of a segment's entry in the cart, a `(u32, u8)`.
+const SEGMENT_BYTES: usize = 8;
+
+/// Split run codes that change between segments of the movie. The first code is
+/// built over the whole movie, and the rest each over a single segment whose runs
+/// they shrink by more than the size of their tables.
+pub struct SegmentedRunCodes {
+    pub codes: Vec<SplitRunCode>,
+    /// First frame and code of each segment.
+    pub segments: Vec<(usize, usize)>,
+}
+
+impl SegmentedRunCodes {
+    pub fn new(data: &[EncodedFrame]) -> Self {
+        let mut codes = vec![SplitRunCode::new(&run_freq(data))];
+        let mut segments = vec![];
+        let starts = segment_starts(data);
+        for (i, &start) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).copied().unwrap_or(data.len());
+            let freq = run_freq(&data[start..end]);
+            let mut code = 0;
+            if !freq.is_empty() {
+                let own = SplitRunCode::new(&freq);
+                let own_size = own.bits(&freq).div_ceil(8) + own.table_bytes() + SEGMENT_BYTES;
+                if own_size < codes[0].bits(&freq).div_ceil(8) {
+                    codes.push(own);
+                    code = codes.len() - 1;
+                }
+            }
+            if segments.last().map(|&(_, last)| last) != Some(code) {
+                segments.push((start, code));
+            }
+        }
+        SegmentedRunCodes { codes, segments }
+    }
+
+    /// The code used for runs in `frame`.
+    pub fn code(&self, frame: usize) -> &SplitRunCode {
+        let segment = self.segments.partition_point(|&(start, _)| start <= frame) - 1;
+        &self.codes[self.segments[segment].1]
+    }
+
+    /// Number of bits the runs of `data` take.
+    pub fn bits(&self, data: &[EncodedFrame]) -> usize {
+        data.iter()
+            .enumerate()
+            .flat_map(|(frame, encoded)| encoded.rects().iter().map(move |rect| (frame, rect)))
+            .flat_map(|(frame, rect)| rect.runs.iter().map(move |run| (frame, run)))
+            .map(|(frame, run)| self.code(frame).code_length(run).unwrap())
+            .sum()
+    }
+
+    pub fn table_bytes(&self) -> usize {
+        let codes: usize = self.codes.iter().map(SplitRunCode::table_bytes).sum();
+        codes + self.segments.len() * SEGMENT_BYTES
+    }
+}
+
+fn run_freq(data: &[EncodedFrame]) -> HashMap<Run, u64> {
+    let mut freq = HashMap::new();
+    for rect in data.iter().flat_map(EncodedFrame::rects) {
+        for &run in &rect.runs {
+            *freq.entry(run).or_default() += 1;
+        }
+    }
+    freq
+}
+
+/// Frames that start a segment: every `SEGMENT_FRAMES` frames, and scene cuts, where
+/// the frame's rects cover more than half of the screen or it is drawn whole.
+fn segment_starts(data: &[EncodedFrame]) -> Vec<usize> {
+    let mut starts = vec![0];
+    for (frame, encoded) in data.iter().enumerate().skip(1) {
+        let since_start = frame - starts.last().unwrap();
+        let scene_cut = match &encoded.coding {
+            FrameCoding::Rects(rects) => {
+                let area: u32 = rects.iter().map(|r| r.rect.w * r.rect.h).sum();
+                area * 2 > RESCALE_WIDTH * RESCALE_HEIGHT
+            }
+            FrameCoding::Quadtree(_) | FrameCoding::Outlines(_) => true,
+        };
+        if since_start >= SEGMENT_FRAMES || (scene_cut && since_start >= MIN_SEGMENT_FRAMES) {
+            starts.push(frame);
+        }
+    }
+    starts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitstream::{bucket_length, huffman_index, BitStream};
+    use crate::references::ReferenceCommand;
+    use crate::{EncodedRect, Rect};
+
+    fn run(kind: u8, length: u32) -> Run {
+        Run { length, kind }
+    }
+
+    /// Reads a run the way the cart's `read_run` does with split codes.
+    fn decode_run(code: &SplitRunCode, stream: &mut BitStream) -> Run {
+        let kind = code.kinds.values()
+            [huffman_index(|| stream.read_one().unwrap(), code.kinds.length_counts())];
+        let buckets = code.buckets[kind as usize].as_ref().unwrap();
+        let bucket =
+            buckets.values()[huffman_index(|| stream.read_one().unwrap(), buckets.length_counts())];
+        run(kind, bucket_length(stream, bucket as u32))
+    }
+
+    /// Writes `runs` with each of their codes followed by a marker, and returns the
+    /// stream's bytes.
+    fn encode_runs<'a>(runs: impl IntoIterator<Item = (&'a SplitRunCode, Run)>) -> Vec<u8> {
+        let mut bits = BitVec::new();
+        for (code, run) in runs {
+            code.encode(&mut bits, &run);
+        }
+        bits.write_int(12345);
+        let mut bytes = vec![];
+        bits.dump(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn length_buckets() {
+        assert_eq!(length_bucket(4), (3, 0, 0));
+        assert_eq!(length_bucket(5), (4, 0, 1));
+        assert_eq!(length_bucket(7), (5, 0, 1));
+
+        let mut bits = BitVec::new();
+        for length in 1..2000 {
+            let (_, extra, extra_bits) = length_bucket(length);
+            bits.write_bits(extra, extra_bits);
+        }
+        let mut bytes = vec![];
+        bits.dump(&mut bytes).unwrap();
+        let mut stream = BitStream::new(&bytes);
+        for length in 1..2000 {
+            let (bucket, _, _) = length_bucket(length);
+            assert_eq!(bucket_length(&mut stream, bucket as u32), length);
+        }
+    }
+
+    #[test]
+    fn kind_with_single_bucket() {
+        let runs = [
+            run(0, 1),
+            run(1, 2),
+            run(0, 1),
+            run(1, 40),
+            run(2, 300),
+            run(1, 5),
+            run(0, 1),
+            run(2, 3),
+        ];
+        let mut freq = HashMap::new();
+        for &run in &runs {
+            *freq.entry(run).or_default() += 1;
+        }
+        let code = SplitRunCode::new(&freq);
+        assert_eq!(code.buckets[0].as_ref().unwrap().values(), &[0]);
+
+        let bytes = encode_runs(runs.iter().map(|&run| (&code, run)));
+        let mut stream = BitStream::new(&bytes);
+        for &run in &runs {
+            assert_eq!(decode_run(&code, &mut stream), run);
+        }
+        assert_eq!(stream.read_int(), Some(12345));
+    }
+
+    /// A frame with a single rect of `runs`.
+    fn frame(runs: Vec<Run>) -> EncodedFrame {
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            w: runs.iter().map(|run| run.length).sum(),
+            h: 1,
+        };
+        EncodedFrame {
+            duration: 1,
+            reference: ReferenceCommand::default(),
+            coding: FrameCoding::Rects(vec![EncodedRect {
+                rect,
+                order: 0,
+                motion: None,
+                xor: false,
+                runs,
+            }]),
+        }
+    }
+
+    #[test]
+    fn segment_with_single_bucket() {
+        // The last segment only has runs of a single pixel of one color, so its own
+        // codes have a single kind and a single bucket.
+        let data: Vec<_> = (0..SEGMENT_FRAMES + 20)
+            .map(|i| match i < SEGMENT_FRAMES {
+                true => frame(vec![run(0, 1 + i as u32 % 7), run(1, 30), run(2, 2)]),
+                false => frame(vec![run(1, 1); 50]),
+            })
+            .collect();
+        let codes = SegmentedRunCodes::new(&data);
+        assert_eq!(codes.segments, [(0, 0), (SEGMENT_FRAMES, 1)]);
+        assert_eq!(codes.codes[1].kinds.values(), &[1]);
+        assert_eq!(codes.codes[1].buckets[1].as_ref().unwrap().values(), &[0]);
+
+        let runs: Vec<_> = data
+            .iter()
+            .enumerate()
+            .flat_map(|(frame, encoded)| {
+                let runs = encoded.rects().iter().flat_map(|rect| &rect.runs);
+                runs.map(move |&run| (frame, run))
+            })
+            .collect();
+        let bytes = encode_runs(runs.iter().map(|&(frame, run)| (codes.code(frame), run)));
+        // Switch codes where a segment starts, as `decode_frame` does.
+        let mut stream = BitStream::new(&bytes);
+        let mut code = 0;
+        for &(frame, run) in &runs {
+            if let Some(&(_, segment_code)) =
+                codes.segments.iter().find(|&&(start, _)| start == frame)
+            {
+                code = segment_code;
+            }
+            assert_eq!(decode_run(&codes.codes[code], &mut stream), run);
+        }
+        assert_eq!(stream.read_int(), Some(12345));
+    }
+}
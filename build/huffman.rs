@@ -1,57 +1,111 @@
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::HashMap;
 use std::hash::Hash;
 use std::io::Write;
 
 use crate::bitvec::BitVec;
+use crate::MAX_CODE_LENGTH;
 
+/// Rough size in bytes of WebAssembly for each `if next()` of a decoder emitted as
+/// nested ifs, and for each value it returns. These are estimates, not measured from
+/// the cart.
+const IF_BYTES: usize = 8;
+const LEAF_BYTES: usize = 3;
+
+/// A canonical Huffman code: codewords are assigned in order of length, so the
+/// code is fully described by its values in codeword order and the number of
+/// codewords of each length.
 pub struct HuffmanCode<T> {
     value_to_codeword: HashMap<T, BitVec>,
     codeword_tree: Code<T>,
+    values: Vec<T>,
+    length_counts: Vec<u16>,
 }
 
 enum Code<T> {
+    /// A code without values, whose decoder is never called.
+    Empty,
     Value(T),
     Split(Box<Code<T>>, Box<Code<T>>),
 }
 
-struct FrequencyCode<T> {
-    freq: u64,
-    count: usize,
-    code: Code<T>,
-}
+impl<T: Hash + Ord + Clone> HuffmanCode<T> {
+    /// Builds an optimal code with no codeword longer than `max_length` bits, using
+    /// the package-merge algorithm.
+    pub fn new(counts: impl IntoIterator<Item = (T, u64)>, max_length: usize) -> HuffmanCode<T> {
+        let mut values: Vec<_> = counts.into_iter().collect();
+        // Ties are broken by value, so that the code doesn't depend on the order the
+        // counts come in, which for a HashMap changes from build to build.
+        values.sort_by(|(a, a_freq), (b, b_freq)| (a_freq, a).cmp(&(b_freq, b)));
+        assert!(
+            values.len() <= 1 << max_length,
+            "{} values don't fit in codewords of {max_length} bits",
+            values.len()
+        );
 
-impl<T: Hash + Eq + Clone> HuffmanCode<T> {
-    pub fn new(counts: impl IntoIterator<Item = (T, u64)>) -> HuffmanCode<T> {
-        let mut pqueue: BinaryHeap<FrequencyCode<T>> = counts
-            .into_iter()
-            .map(|(v, freq)| FrequencyCode {
-                freq,
-                count: 1,
-                code: Code::Value(v),
-            })
+        // Each item is a weight and the values it contains. Every time a value is
+        // among the 2n - 2 lightest items of the final list adds a bit to its length.
+        let leaves: Vec<_> = values
+            .iter()
+            .enumerate()
+            .map(|(i, &(_, freq))| (freq, vec![i]))
             .collect();
+        let mut items = leaves.clone();
+        for _ in 1..max_length {
+            let packages = items.chunks_exact(2).map(|pair| {
+                let mut contents = pair[0].1.clone();
+                contents.extend_from_slice(&pair[1].1);
+                (pair[0].0 + pair[1].0, contents)
+            });
+            items = leaves.iter().cloned().chain(packages).collect();
+            items.sort_by_key(|&(freq, _)| freq);
+        }
 
-        while pqueue.len() > 1 {
-            let mut next_2 = pqueue.pop().unwrap();
-            let mut next_1 = pqueue.pop().unwrap();
-            if next_2.count < next_1.count {
-                std::mem::swap(&mut next_1, &mut next_2);
+        let mut lengths = vec![0; values.len()];
+        for (_, contents) in items.iter().take((2 * values.len()).saturating_sub(2)) {
+            for &i in contents {
+                lengths[i] += 1;
+            }
+        }
+        Self::canonical(values.into_iter().map(|(v, _)| v).zip(lengths).collect())
+    }
+
+    fn canonical(mut lengths: Vec<(T, usize)>) -> HuffmanCode<T> {
+        lengths.sort_by_key(|&(_, length)| length);
+        let max_length = lengths.last().map_or(0, |&(_, length)| length);
+
+        let mut length_counts = vec![0u16; max_length];
+        for &(_, length) in &lengths {
+            if length > 0 {
+                length_counts[length - 1] += 1;
             }
-            pqueue.push(FrequencyCode {
-                freq: next_1.freq + next_2.freq,
-                count: next_1.count + next_2.count,
-                code: Code::Split(Box::new(next_1.code), Box::new(next_2.code)),
-            });
         }
 
-        let codeword_tree = pqueue.pop().unwrap().code;
+        let mut codewords = vec![];
+        let mut code = 0u32;
+        let mut code_length = 0;
+        for (value, length) in lengths {
+            if !codewords.is_empty() {
+                code += 1;
+            }
+            code <<= length - code_length;
+            code_length = length;
+            codewords.push((value, code, length));
+        }
 
         let mut value_to_codeword = HashMap::new();
-        build_value_map(&mut value_to_codeword, BitVec::new(), &codeword_tree);
+        for (value, code, length) in &codewords {
+            let mut codeword = BitVec::new();
+            for i in (0..*length).rev() {
+                codeword.write(code >> i & 1 != 0);
+            }
+            value_to_codeword.insert(value.clone(), codeword);
+        }
 
         HuffmanCode {
             value_to_codeword,
-            codeword_tree,
+            codeword_tree: codeword_tree(&codewords, 0),
+            values: codewords.into_iter().map(|(value, _, _)| value).collect(),
+            length_counts,
         }
     }
 
@@ -59,42 +113,79 @@ impl<T: Hash + Eq + Clone> HuffmanCode<T> {
         into.append(self.value_to_codeword.get(v).unwrap());
     }
 
-    pub fn emit_decoder<W: Write>(
+    pub fn code_length(&self, v: &T) -> Option<usize> {
+        self.value_to_codeword.get(v).map(BitVec::len)
+    }
+
+    /// Emits a function `name` that decodes a value of type `V`, either as nested ifs
+    /// or as a table for the cart's `huffman_index`. It picks whichever is estimated
+    /// to be smaller: the ifs from rough sizes of WebAssembly instructions, the table
+    /// from the size of its counts and of `V`. Neither is measured from the cart.
+    pub fn emit_decoder<V, W: Write>(
         &self,
         to: &mut W,
         name: &str,
-        ty: &str,
         mut emit_code: impl FnMut(&mut W, &T) -> std::io::Result<()>,
     ) -> std::io::Result<()> {
-        write!(to, "pub fn {name}(mut next: impl FnMut() -> bool) -> {ty} {{")?;
-        emit_codeword_decoder(to, &mut emit_code, &self.codeword_tree)?;
-        write!(to, "}}")
+        let ty = std::any::type_name::<V>();
+        let splits = self.values.len().saturating_sub(1);
+        let ifs_size = splits * IF_BYTES + self.values.len() * LEAF_BYTES;
+        let table_size = MAX_CODE_LENGTH * 2 + self.values.len() * size_of::<V>();
+        if ifs_size <= table_size {
+            // A code with a single value or none reads no bits.
+            let next = match splits {
+                0 => "_next",
+                _ => "mut next",
+            };
+            write!(to, "pub fn {name}({next}: impl FnMut() -> bool) -> {ty} {{")?;
+            emit_codeword_decoder(to, &mut emit_code, &self.codeword_tree)?;
+            return write!(to, "}}");
+        }
+
+        let table = name.to_uppercase();
+        let mut length_counts = self.length_counts.clone();
+        length_counts.resize(MAX_CODE_LENGTH, 0);
+        write!(
+            to,
+            "const {table}_LENGTH_COUNTS: [u16; MAX_CODE_LENGTH] = {length_counts:?};
+            const {table}_VALUES: &[{ty}] = &["
+        )?;
+        for value in &self.values {
+            emit_code(to, value)?;
+            write!(to, ",")?;
+        }
+        write!(
+            to,
+            "];
+            pub fn {name}(next: impl FnMut() -> bool) -> {ty} {{
+                {table}_VALUES[super::huffman_index(next, &{table}_LENGTH_COUNTS)]
+            }}"
+        )
     }
 
-    pub fn structure(&self) -> (BitVec, Vec<T>) {
-        let mut tree = BitVec::new();
-        let mut values = vec![];
-        structure_bits(&mut tree, &mut values, &self.codeword_tree);
-        (tree, values)
+    /// The values in codeword order.
+    pub fn values(&self) -> &[T] {
+        &self.values
+    }
+
+    /// How many codewords there are of each length, starting from 1 bit.
+    pub fn length_counts(&self) -> &[u16] {
+        &self.length_counts
     }
 }
 
-fn build_value_map<T: Hash + Eq + Clone>(
-    map: &mut HashMap<T, BitVec>,
-    mut codeword: BitVec,
-    tree: &Code<T>,
-) {
-    match tree {
-        Code::Value(v) => {
-            let old = map.insert(v.clone(), codeword);
-            assert!(old.is_none());
-        }
-        Code::Split(zero, one) => {
-            let mut z = codeword.clone();
-            z.write(false);
-            build_value_map(map, z, zero);
-            codeword.write(true);
-            build_value_map(map, codeword, one);
+/// Builds the decoding tree for `codewords` that share their first `depth` bits.
+fn codeword_tree<T: Clone>(codewords: &[(T, u32, usize)], depth: usize) -> Code<T> {
+    match codewords {
+        [] => Code::Empty,
+        [(value, _, length)] if *length == depth => Code::Value(value.clone()),
+        _ => {
+            let split = codewords
+                .partition_point(|&(_, code, length)| code >> (length - depth - 1) & 1 == 0);
+            Code::Split(
+                Box::new(codeword_tree(&codewords[..split], depth + 1)),
+                Box::new(codeword_tree(&codewords[split..], depth + 1)),
+            )
         }
     }
 }
@@ -105,6 +196,7 @@ fn emit_codeword_decoder<T, W: Write>(
     code: &Code<T>,
 ) -> std::io::Result<()> {
     match code {
+        Code::Empty => write!(to, "unreachable!()"),
         Code::Value(v) => emit_code(to, v),
         Code::Split(zero, one) => {
             write!(to, "if next() {{")?;
@@ -116,36 +208,143 @@ fn emit_codeword_decoder<T, W: Write>(
     }
 }
 
-fn structure_bits<T: Clone>(tree: &mut BitVec, value: &mut Vec<T>, code: &Code<T>) {
-    match code {
-        Code::Value(v) => {
-            tree.write(true);
-            value.push(v.clone());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitstream::{huffman_index, BitStream};
+
+    /// Codes `values` with a code built from `counts`, then decodes them with the cart's
+    /// decoder, checking that it reads exactly the bits that were written.
+    fn round_trip(counts: &[(u32, u64)], values: &[u32]) {
+        round_trip_code(
+            &HuffmanCode::new(counts.iter().copied(), MAX_CODE_LENGTH),
+            values,
+        );
+    }
+
+    fn round_trip_code(code: &HuffmanCode<u32>, values: &[u32]) {
+        let mut bits = BitVec::new();
+        for value in values {
+            code.encode_value(&mut bits, value);
         }
-        Code::Split(zero, one) => {
-            tree.write(false);
-            structure_bits(tree, value, zero);
-            structure_bits(tree, value, one);
-        },
+        bits.write_int(12345);
+        let mut bytes = vec![];
+        bits.dump(&mut bytes).unwrap();
+
+        let mut stream = BitStream::new(&bytes);
+        for value in values {
+            let index = huffman_index(|| stream.read_one().unwrap(), code.length_counts());
+            assert_eq!(code.values()[index], *value);
+        }
+        assert_eq!(stream.read_int(), Some(12345));
     }
-}
 
-impl<T> Ord for FrequencyCode<T> {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.freq.cmp(&other.freq).reverse()
+    #[test]
+    fn round_trips() {
+        let counts = [(1, 40), (2, 30), (3, 10), (4, 10), (5, 5), (6, 4), (7, 1)];
+        round_trip(&counts, &[1, 7, 2, 2, 6, 3, 5, 4, 1]);
+    }
+
+    /// Counts whose unlimited Huffman code is as deep as it gets: each count is the
+    /// sum of the two before it.
+    fn fibonacci_counts(n: u32) -> Vec<(u32, u64)> {
+        let (mut a, mut b) = (1, 1);
+        (0..n)
+            .map(|value| {
+                (a, b) = (b, a + b);
+                (value, a)
+            })
+            .collect()
     }
-}
 
-impl<T> PartialOrd for FrequencyCode<T> {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
+    /// Bits it takes to code every value as often as its count, checking on the way
+    /// that the code is complete and no codeword is longer than `max_length`.
+    fn coded_bits(counts: &[(u32, u64)], max_length: usize) -> u64 {
+        let code = HuffmanCode::new(counts.iter().copied(), max_length);
+        let mut kraft = 0.0;
+        let mut bits = 0;
+        for (value, count) in counts {
+            let length = code.code_length(value).unwrap();
+            assert!(length <= max_length);
+            kraft += 0.5f64.powi(length as i32);
+            bits += count * length as u64;
+        }
+        assert_eq!(kraft, 1.0);
+        bits
     }
-}
 
-impl<T> PartialEq for FrequencyCode<T> {
-    fn eq(&self, other: &Self) -> bool {
-        self.freq == other.freq
+    /// Bits an unlimited Huffman code takes, by merging the two lightest weights until
+    /// one is left.
+    fn huffman_bits(counts: &[(u32, u64)]) -> u64 {
+        let mut weights: Vec<_> = counts.iter().map(|&(_, count)| count).collect();
+        let mut bits = 0;
+        while weights.len() > 1 {
+            weights.sort_unstable_by(|a, b| b.cmp(a));
+            let merged = weights.pop().unwrap() + weights.pop().unwrap();
+            bits += merged;
+            weights.push(merged);
+        }
+        bits
     }
-}
 
-impl<T> Eq for FrequencyCode<T> {}
+    #[test]
+    fn limits_code_lengths() {
+        let counts = fibonacci_counts(20);
+        // Unlimited, the rarest values would take 19 bits.
+        assert_eq!(coded_bits(&counts, 19), huffman_bits(&counts));
+        for max_length in [5, 8, 12] {
+            assert!(coded_bits(&counts, max_length) > huffman_bits(&counts));
+        }
+        let code = HuffmanCode::new(counts.iter().copied(), 5);
+        let values: Vec<_> = counts.iter().map(|&(value, _)| value).collect();
+        round_trip_code(&code, &values);
+    }
+
+    #[test]
+    fn optimal_within_limit() {
+        let counts = [(1, 40), (2, 30), (3, 10), (4, 10), (5, 5), (6, 4), (7, 1)];
+        assert_eq!(coded_bits(&counts, MAX_CODE_LENGTH), huffman_bits(&counts));
+        // In 3 bits, seven values leave room for one shorter codeword, which goes to
+        // the commonest.
+        assert_eq!(
+            coded_bits(&counts, 3),
+            40 * 2 + (30 + 10 + 10 + 5 + 4 + 1) * 3
+        );
+    }
+
+    #[test]
+    fn fills_every_codeword_of_the_limit() {
+        let counts: Vec<_> = (0..16).map(|value| (value, 1 + value as u64)).collect();
+        let code = HuffmanCode::new(counts.iter().copied(), 4);
+        assert_eq!(code.length_counts(), [0, 0, 0, 16]);
+    }
+
+    #[test]
+    fn ties_ignore_count_order() {
+        let counts: Vec<_> = (0..20u32)
+            .map(|value| (value, 1 + value as u64 % 3))
+            .collect();
+        let code = HuffmanCode::new(counts.iter().copied(), MAX_CODE_LENGTH);
+        let reversed = HuffmanCode::new(counts.iter().rev().copied(), MAX_CODE_LENGTH);
+        assert_eq!(code.values(), reversed.values());
+        assert_eq!(code.length_counts(), reversed.length_counts());
+    }
+
+    #[test]
+    fn single_value_has_no_bits() {
+        let code = HuffmanCode::new([(9u32, 5)], MAX_CODE_LENGTH);
+        assert_eq!(code.code_length(&9), Some(0));
+        assert!(code.length_counts().iter().all(|&count| count == 0));
+        round_trip(&[(9, 5)], &[9, 9, 9]);
+    }
+
+    #[test]
+    fn single_value_decoder_reads_nothing() {
+        let code = HuffmanCode::new([(9u32, 5)], MAX_CODE_LENGTH);
+        let mut decoder = vec![];
+        code.emit_decoder::<u32, _>(&mut decoder, "decode", |to, v| write!(to, "{v}"))
+            .unwrap();
+        let decoder = String::from_utf8(decoder).unwrap();
+        assert!(!decoder.contains("next()"), "{decoder}");
+    }
+}
@@ -0,0 +1,188 @@
+use image::GrayImage;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::outline::encode_outlines;
+use crate::quadtree::encode_quadtree;
+use crate::references::{ReferenceCommand, ReferencePlan};
+use crate::{
+    encode_frame, encode_frames, encode_movie, merge_unchanged, EncodedFrame, FrameCoding,
+    MotionCache, Movie, Pricing,
+};
+
+/// Number of bisection steps used to find the smallest distortion that fits.
+const SEARCH_STEPS: u32 = 10;
+
+/// Encodes the movie with as few pixel errors as possible while fitting in `budget` bytes.
+///
+/// Each frame picks whichever of a handful of approximations of the real frame minimizes
+/// `errors + lambda * bits`, and `lambda` is searched for the smallest value that fits.
+pub fn encode_to_budget(
+    images: &[GrayImage],
+    durations: &[u32],
+    budget: usize,
+) -> Vec<EncodedFrame> {
+    // Lossless when it fits, otherwise only the first pass, whose codes the search
+    // starts from.
+    let lossless = encode_frames(images, durations, Some(budget));
+    let reference = encode_movie(&lossless);
+    if reference.size() <= budget {
+        return lossless;
+    }
+
+    let mut low = 0.0;
+    let mut high = 1.0 / 64.0;
+    let mut best = loop {
+        let (data, errors) = encode_lossy(images, durations, &reference, high);
+        let size = encode_movie(&data).size();
+        println!("cargo:warning=Lambda {high}: {size} bytes, {errors} pixel errors");
+        if size <= budget {
+            break data;
+        }
+        if data
+            .iter()
+            .all(|encoded| matches!(&encoded.coding, FrameCoding::Rects(rects) if rects.is_empty()))
+        {
+            panic!("video does not fit in {budget} bytes even with every frame dropped");
+        }
+        low = high;
+        high *= 2.0;
+    };
+
+    for _ in 0..SEARCH_STEPS {
+        let lambda = (low + high) / 2.0;
+        let (data, errors) = encode_lossy(images, durations, &reference, lambda);
+        let size = encode_movie(&data).size();
+        println!("cargo:warning=Lambda {lambda}: {size} bytes, {errors} pixel errors");
+        if size <= budget {
+            high = lambda;
+            best = data;
+        } else {
+            low = lambda;
+        }
+    }
+
+    best
+}
+
+/// Encodes every frame against the previous reconstructed frame, or the reconstructed
+/// reference the plan has for it, choosing the candidate with the lowest rate-distortion
+/// cost under `movie`'s codes. Frames left unchanged are merged into the one before.
+fn encode_lossy(
+    images: &[GrayImage],
+    durations: &[u32],
+    movie: &Movie,
+    lambda: f64,
+) -> (Vec<EncodedFrame>, usize) {
+    let plan = ReferencePlan::new(images);
+    let mut references = vec![images[0].clone(); plan.slots];
+    let mut reconstructed = images[0].clone();
+    let mut data = vec![];
+    let mut total_errors = 0;
+    for (frame, target) in images[1..].iter().enumerate() {
+        let store = plan.stores[frame];
+        let mut bases = vec![(None, &reconstructed)];
+        if let Some((slot, _)) = plan.restores[frame] {
+            bases.push((Some(slot), &references[slot]));
+        }
+        let (candidate, encoded, errors, _) = candidates(target, &reconstructed)
+            .into_par_iter()
+            .flat_map_iter(|candidate| {
+                let frame_coding = |restore, coding| EncodedFrame {
+                    duration: durations[frame],
+                    reference: ReferenceCommand { restore, store },
+                    coding,
+                };
+                let mut codings: Vec<_> = bases
+                    .iter()
+                    .flat_map(|&(restore, base)| {
+                        [
+                            encode_frame(
+                                &candidate,
+                                base,
+                                Pricing::Bits(movie, frame),
+                                &mut MotionCache::new(),
+                            ),
+                            encode_quadtree(&candidate, base),
+                        ]
+                        .map(|coding| frame_coding(restore, coding))
+                    })
+                    .collect();
+                codings.push(frame_coding(None, encode_outlines(&candidate)));
+                let errors = pixel_errors(&candidate, target);
+                codings.into_iter().map(move |encoded| {
+                    // Unchanged frames are merged into the one before, see `merge_unchanged`.
+                    let bits = match encoded.is_unchanged() {
+                        true => 0,
+                        false => movie.frame_bits(frame, &encoded).total(),
+                    };
+                    let cost = errors as f64 + lambda * bits as f64;
+                    (candidate.clone(), encoded, errors, cost)
+                })
+            })
+            .min_by(|a, b| a.3.total_cmp(&b.3))
+            .unwrap();
+        if let Some(slot) = store {
+            references[slot] = candidate.clone();
+        }
+        reconstructed = candidate;
+        data.push(encoded);
+        total_errors += errors;
+    }
+    (merge_unchanged(data), total_errors)
+}
+
+fn candidates(target: &GrayImage, prev: &GrayImage) -> Vec<GrayImage> {
+    vec![
+        target.clone(),
+        defer_isolated_changes(target, prev),
+        defer_isolated_changes(&remove_speckles(target), prev),
+        prev.clone(),
+    ]
+}
+
+/// Keeps the previous value of changed pixels that have no changed neighbours.
+fn defer_isolated_changes(target: &GrayImage, prev: &GrayImage) -> GrayImage {
+    let changed = |x: u32, y: u32| target.get_pixel(x, y) != prev.get_pixel(x, y);
+    let mut result = target.clone();
+    for (x, y, pixel) in result.enumerate_pixels_mut() {
+        if changed(x, y) && neighbours(target, x, y, true).all(|(nx, ny)| !changed(nx, ny)) {
+            *pixel = *prev.get_pixel(x, y);
+        }
+    }
+    result
+}
+
+/// Replaces pixels whose four neighbours all share a different colour with that colour.
+fn remove_speckles(target: &GrayImage) -> GrayImage {
+    let mut result = target.clone();
+    for (x, y, pixel) in result.enumerate_pixels_mut() {
+        let mut colours = neighbours(target, x, y, false).map(|(nx, ny)| target.get_pixel(nx, ny));
+        let first = colours.next().unwrap();
+        if first != target.get_pixel(x, y) && colours.all(|c| c == first) {
+            *pixel = *first;
+        }
+    }
+    result
+}
+
+fn neighbours(
+    image: &GrayImage,
+    x: u32,
+    y: u32,
+    diagonals: bool,
+) -> impl Iterator<Item = (u32, u32)> + '_ {
+    (-1..=1)
+        .flat_map(|dy| (-1..=1).map(move |dx| (dx, dy)))
+        .filter(move |&(dx, dy): &(i32, i32)| {
+            (dx, dy) != (0, 0) && (diagonals || dx == 0 || dy == 0)
+        })
+        .map(move |(dx, dy)| (x as i32 + dx, y as i32 + dy))
+        .filter(|&(nx, ny)| {
+            nx >= 0 && ny >= 0 && (nx as u32) < image.width() && (ny as u32) < image.height()
+        })
+        .map(|(nx, ny)| (nx as u32, ny as u32))
+}
+
+fn pixel_errors(a: &GrayImage, b: &GrayImage) -> usize {
+    a.pixels().zip(b.pixels()).filter(|(a, b)| a != b).count()
+}
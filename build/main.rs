@@ -1,22 +1,53 @@
 use std::collections::HashMap;
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{BufWriter, Write};
 use std::path::Path;
 use std::process::Command;
 
 use image::imageops::{ColorMap, FilterType};
 use image::{imageops, GrayImage, Rgb};
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
 use rayon::slice::ParallelSlice;
 
 use crate::bitvec::BitVec;
+use crate::buckets::SegmentedRunCodes;
 use crate::huffman::HuffmanCode;
+use crate::outline::{encode_outlines, Outlines};
+use crate::playback::{AUDIO_OFFSET, LOOP_MUSIC, TICK_RATE};
+use crate::quadtree::encode_quadtree;
+use crate::rans::RansCode;
+use crate::references::{ReferenceCommand, ReferencePlan};
 
+mod arithmetic;
 mod bitvec;
+mod buckets;
 mod huffman;
+mod lossy;
+mod outline;
+mod playback;
+mod quadtree;
+mod rans;
+mod references;
+mod stats;
 
-const FRAMERATE: u32 = 7;
+// The cart's pixel orders, which the encoder visits rects in.
+#[path = "../src/order.rs"]
+mod order;
+// The cart's decoders, which the tests read the encoders' output back with.
+#[cfg(test)]
+#[allow(dead_code)]
+#[path = "../src/bitstream.rs"]
+mod bitstream;
+
+/// Rate frames are read at, and the highest they are shown at. Frames may be shown for
+/// longer, see `AVERAGE_FRAMERATE`.
+const FRAMERATE: u32 = 15;
+/// Rate of the frames kept, on average over the video. The frames that change the
+/// fewest pixels from the last frame kept are dropped and the frame before them shown
+/// for longer, so the framerate is lower where there is little motion and up to
+/// `FRAMERATE` where there is a lot. Repeated frames are always dropped.
+const AVERAGE_FRAMERATE: u32 = 7;
 const RESCALE_WIDTH: u32 = 40;
 const RESCALE_HEIGHT: u32 = 30;
 const PALETTE: &[Rgb<u8>] = &[
@@ -28,9 +59,33 @@ const PALETTE: &[Rgb<u8>] = &[
 const START_OFFSET: u32 = 30;
 const MAX_FRAMES: u32 = u32::MAX;
 const DOWNSCALE_FILTER: FilterType = FilterType::Gaussian;
+/// Maximum size in bytes of the encoded video. When set, the encoder introduces small
+/// errors where they save the most space until the video fits.
+const TARGET_SIZE: Option<usize> = None;
+/// How runs are entropy coded: with static Huffman codes in the movie stream, or in a
+/// stream of their own (`runs.bin`) with an adaptive binary range coder or with rANS.
+const RUN_CODER: RunCoder = RunCoder::Huffman;
+/// Longest allowed Huffman codeword, in bits.
+const MAX_CODE_LENGTH: usize = 12;
 
 const BPP: u32 = PALETTE.len().trailing_zeros();
 const UNCHANGED_BIT: u32 = 1 << BPP;
+/// Size of WASM-4's linear memory, unless overridden in .cargo/config.toml, and of the
+/// region at its start used by the runtime.
+const WASM4_MEMORY: usize = 0x10000;
+const WASM4_RESERVED: usize = 0x19A0;
+/// Allowance for the cart's own static data besides the encoded assets.
+const CART_DATA_ESTIMATE: usize = 1024;
+/// Estimated cost of a symbol that is missing from a Huffman code.
+const UNSEEN_SYMBOL_BITS: usize = 20;
+
+#[allow(dead_code)] // only the coder selected by RUN_CODER is constructed
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum RunCoder {
+    Huffman,
+    Arithmetic,
+    Rans,
+}
 
 struct Palette;
 
@@ -60,13 +115,13 @@ impl ColorMap for Palette {
     }
 }
 
-#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Debug)]
 struct Run {
     length: u32,
     kind: u8,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Hash, PartialEq, Eq)]
 struct Rect {
     x: u32,
     y: u32,
@@ -82,6 +137,127 @@ impl Rect {
     fn ys(self) -> impl Iterator<Item = u32> {
         self.y..self.y + self.h
     }
+
+    /// Whether the rect has a header, which thin rects leave out. The cart draws
+    /// rects without one in the first order, without XOR.
+    fn has_header(self) -> bool {
+        self.w != 1 && self.h != 1
+    }
+}
+
+/// Number of pixel traversal orders, see `get_xy` in `src/order.rs`.
+const ORDERS: usize = 10;
+/// Flag in a rect's header, after its order, for rects predicted by motion.
+const MOTION_FLAG: usize = 1;
+/// Flag in a rect's header for rects whose runs are XORed onto the previous frame.
+const XOR_FLAG: usize = 2;
+/// Furthest a rect can be predicted from, in pixels in each direction.
+const MOTION_RANGE: i32 = 4;
+/// Most motion offsets coded for each rect, out of those that predict the fewest pixels
+/// wrong.
+const MOTION_CANDIDATES: usize = 4;
+
+/// Every coding of motion predicted rects, by the rect, its offset and the pixels it
+/// is predicted from. Kept between passes, where mostly the same predictions are tried.
+type MotionCache = HashMap<(Rect, (i32, i32), Vec<u8>), Vec<EncodedRect>>;
+
+#[derive(Clone)]
+struct EncodedRect {
+    rect: Rect,
+    order: usize,
+    /// Offset of the region of the previous frame the rect is predicted from, if
+    /// not the rect itself.
+    motion: Option<(i32, i32)>,
+    /// Whether the runs' kinds are XORed onto the pixels rather than replacing them.
+    xor: bool,
+    runs: Vec<Run>,
+}
+
+impl EncodedRect {
+    fn has_header(&self) -> bool {
+        self.rect.has_header()
+    }
+
+    /// The rect's order and flags, coded together with the order Huffman code.
+    fn header(&self) -> usize {
+        let mut flags = 0;
+        if self.motion.is_some() {
+            flags |= MOTION_FLAG;
+        }
+        if self.xor {
+            flags |= XOR_FLAG;
+        }
+        self.order + ORDERS * flags
+    }
+}
+
+/// Number of rects symbol that marks a frame coded as a quadtree.
+const QUADTREE_FRAME: usize = u32::MAX as usize;
+/// Number of rects symbol that marks a frame starting with a reference command, followed
+/// by the real number of rects.
+const REFERENCE_FRAME: usize = QUADTREE_FRAME - 1;
+/// Number of rects symbol that marks a frame shown for longer than one frame at
+/// `FRAMERATE`, followed by how long, then the real number of rects.
+const DURATION_FRAME: usize = QUADTREE_FRAME - 2;
+/// Number of rects symbol that marks a frame drawn from outlines.
+const OUTLINE_FRAME: usize = QUADTREE_FRAME - 3;
+/// Times each frame's coding is picked again under the codes of the previous pick.
+const CODING_PASSES: usize = 2;
+/// Most times frames are coded, each pass under the codes of the one before.
+const MAX_PASSES: usize = 8;
+
+#[derive(Clone)]
+struct EncodedFrame {
+    /// Number of frames at `FRAMERATE` the frame is shown for.
+    duration: u32,
+    reference: ReferenceCommand,
+    coding: FrameCoding,
+}
+
+#[derive(Clone)]
+enum FrameCoding {
+    Rects(Vec<EncodedRect>),
+    /// Node symbols of a quadtree over the whole frame, in preorder.
+    Quadtree(Vec<u8>),
+    Outlines(Outlines),
+}
+
+impl EncodedFrame {
+    /// The frame's rects, none for a quadtree or outlines.
+    fn rects(&self) -> &[EncodedRect] {
+        match &self.coding {
+            FrameCoding::Rects(rects) => rects,
+            FrameCoding::Quadtree(_) | FrameCoding::Outlines(_) => &[],
+        }
+    }
+
+    /// The symbol that starts the frame's pixels in the movie stream.
+    fn num_rects(&self) -> usize {
+        match &self.coding {
+            FrameCoding::Rects(rects) => rects.len(),
+            FrameCoding::Quadtree(_) => QUADTREE_FRAME,
+            FrameCoding::Outlines(_) => OUTLINE_FRAME,
+        }
+    }
+
+    /// Whether the frame leaves the screen as it is.
+    fn is_unchanged(&self) -> bool {
+        self.reference.is_empty()
+            && matches!(&self.coding, FrameCoding::Rects(rects) if rects.is_empty())
+    }
+}
+
+/// Merges frames that leave the screen as it is into the frame before them, which is
+/// shown for longer instead.
+fn merge_unchanged(data: Vec<EncodedFrame>) -> Vec<EncodedFrame> {
+    let mut merged: Vec<EncodedFrame> = vec![];
+    for encoded in data {
+        match merged.last_mut() {
+            Some(last) if encoded.is_unchanged() => last.duration += encoded.duration,
+            _ => merged.push(encoded),
+        }
+    }
+    merged
 }
 
 fn main() {
@@ -115,83 +291,46 @@ fn main() {
         .collect::<Result<Vec<_>, _>>()
         .unwrap();
 
-    let data: Vec<_> = images
-        .par_windows(2)
-        .map(|v| encode_frame(&v[1], &v[0]))
-        .collect();
+    let source_frames = images.len() - 1;
+    let max_frames = source_frames * AVERAGE_FRAMERATE as usize / FRAMERATE as usize;
+    let (images, durations) = drop_frames(images, max_frames.max(1));
+    let data = match TARGET_SIZE {
+        Some(budget) => lossy::encode_to_budget(&images, &durations, budget),
+        None => encode_frames(&images, &durations, None),
+    };
 
-    let mut run_freq = HashMap::new();
-    let mut orderings = [0; 4];
-    let mut num_rects = vec![];
-    let mut biggest_run = 0;
-    for rects in &data {
-        for &(rect, order, ref runs) in rects {
-            if rect.h != 1 && rect.w != 1 {
-                orderings[order] += 1;
-            }
-            for &run in runs {
-                *run_freq.entry(run).or_default() += 1;
-                let encoded = run.kind as u32 + run.length * (PALETTE.len() as u32 + 1);
-                if encoded > biggest_run {
-                    biggest_run = encoded;
-                }
-            }
-        }
-        while rects.len() >= num_rects.len() {
-            num_rects.push(0);
-        }
-        num_rects[rects.len()] += 1;
-    }
-
-    let mut kind_freq = [0; 5];
-    for run in run_freq.keys() {
-        kind_freq[run.kind as usize] += 1;
-    }
-
-    let runs_huffman = HuffmanCode::new(run_freq);
-
-    let (structure, values) = runs_huffman.structure();
-    let run_value_bits = (biggest_run + 1).next_power_of_two().trailing_zeros();
-
-    let mut runs_data = BitVec::new();
-    for run in values {
-        runs_data.write_bits(
-            run.kind as u32 + run.length * (PALETTE.len() as u32 + 1),
-            run_value_bits,
+    let frames = data.len();
+    let encoded = encode_movie(&data);
+    stats::write_report(&data, &encoded, env::var("OUT_DIR").unwrap()).unwrap();
+    println!("cargo:warning=Frames {frames} of {source_frames}");
+    println!("cargo:warning=Movie size {}", encoded.size());
+    if let Some((joint_size, split_size, segments)) = encoded.run_code_sizes {
+        println!(
+            "cargo:warning=Runs with joint codes {joint_size} bytes, split codes {split_size} \
+            bytes in {segments} segments"
         );
     }
-
-    let order_huffman = HuffmanCode::new(orderings.into_iter().enumerate().filter(|&(_, v)| v > 0));
-
-    let num_rects_huffman =
-        HuffmanCode::new(num_rects.into_iter().enumerate().filter(|&(_, v)| v > 0));
-
-    let frames = data.len();
-    let mut movie = BitVec::new();
-    for rects in data {
-        num_rects_huffman.encode_value(&mut movie, &rects.len());
-        let mut last_index = -1;
-        for (rect, order, runs) in rects {
-            let i = rect.y * RESCALE_WIDTH + rect.x;
-            let br = (rect.y + rect.h - 1) * RESCALE_WIDTH + rect.x + rect.w;
-            movie.write_int((i as i32 - last_index) as u32);
-            movie.write_int(br - i);
-            last_index = i as i32;
-
-            if rect.h != 1 && rect.w != 1 {
-                order_huffman.encode_value(&mut movie, &order);
-            }
-            for run in runs {
-                runs_huffman.encode_value(&mut movie, &run);
-            }
-        }
+    if encoded.run_coder != RUN_CODER {
+        println!("cargo:warning=Runs are Huffman coded, as rANS would be bigger");
     }
-
-    println!("cargo:warning=Frames {frames}");
-    println!(
-        "cargo:warning=Movie size {}",
-        movie.bytes() + structure.bytes() + runs_data.bytes()
-    );
+    let Movie {
+        movie,
+        run_length_counts,
+        runs_data,
+        run_value_bits,
+        runs,
+        rans,
+        split_runs,
+        order_huffman,
+        num_rects_huffman,
+        quadtree_huffman,
+        reference_slots,
+        reference_huffman,
+        turn_huffman,
+        outline_edge_bytes,
+        run_coder,
+        ..
+    } = encoded;
 
     movie
         .dump(BufWriter::new(
@@ -199,18 +338,14 @@ fn main() {
         ))
         .unwrap();
 
-    structure
-        .dump(BufWriter::new(
-            File::create(format!("{}/runs-tree.bin", env::var("OUT_DIR").unwrap())).unwrap(),
-        ))
-        .unwrap();
-
     runs_data
         .dump(BufWriter::new(
             File::create(format!("{}/runs-data.bin", env::var("OUT_DIR").unwrap())).unwrap(),
         ))
         .unwrap();
 
+    fs::write(format!("{}/runs.bin", env::var("OUT_DIR").unwrap()), &runs).unwrap();
+
     let mut code_file = BufWriter::new(
         File::create(format!("{}/generated.rs", env::var("OUT_DIR").unwrap())).unwrap(),
     );
@@ -222,20 +357,67 @@ fn main() {
         pub const HEIGHT: u32 = {RESCALE_HEIGHT};
         pub const FRAMECOUNT: u32 = {frames};
         pub const FRAMERATE: u32 = {FRAMERATE};
-        pub const RUN_DATA_SIZE: u32 = {run_value_bits};"
+        pub const TICK_RATE: u32 = {TICK_RATE};
+        pub const LOOP_MUSIC: bool = {LOOP_MUSIC};
+        pub const RUN_DATA_SIZE: u32 = {run_value_bits};
+        pub const MAX_CODE_LENGTH: usize = {MAX_CODE_LENGTH};
+        pub const RUN_LENGTH_COUNTS: [u16; MAX_CODE_LENGTH] = {run_length_counts:?};
+        pub const RUN_CODER: super::RunCoder = super::RunCoder::{run_coder:?};"
     )
     .unwrap();
 
     order_huffman
-        .emit_decoder(&mut code_file, "decode_order", "u32", |to, order| {
+        .emit_decoder::<u8, _>(&mut code_file, "decode_order", |to, order| {
             write!(to, "{order}")
         })
         .unwrap();
     num_rects_huffman
-        .emit_decoder(&mut code_file, "decode_num_rects", "u32", |to, order| {
+        .emit_decoder::<u32, _>(&mut code_file, "decode_num_rects", |to, order| {
             write!(to, "{order}")
         })
         .unwrap();
+    quadtree_huffman
+        .emit_decoder::<u32, _>(&mut code_file, "decode_quadtree_node", |to, node| {
+            write!(to, "{node}")
+        })
+        .unwrap();
+    // Split into the slots to restore and store, so the cart doesn't divide by a
+    // constant that is 1 when there are no references.
+    reference_huffman
+        .emit_decoder::<(usize, usize), _>(&mut code_file, "decode_reference", |to, reference| {
+            let (restore, store) = ReferenceCommand::slots(*reference, reference_slots);
+            write!(to, "({restore}, {store})")
+        })
+        .unwrap();
+    turn_huffman
+        .emit_decoder::<u8, _>(&mut code_file, "decode_turn", |to, turn| {
+            write!(to, "{turn}")
+        })
+        .unwrap();
+    write!(
+        code_file,
+        "pub const REFERENCE_SLOTS: usize = {reference_slots};
+        pub const OUTLINE_EDGE_BYTES: usize = {outline_edge_bytes};"
+    )
+    .unwrap();
+
+    write_split_run_tables(&mut code_file, split_runs.as_ref()).unwrap();
+
+    let (rans_type, rans_runs, rans_cumulative) = match &rans {
+        Some(code) => (code.value_type(), code.values(), code.cumulative.clone()),
+        None => ("u32", vec![], vec![]),
+    };
+    let rans_runs: Vec<_> = rans_runs.iter().map(u32::to_string).collect();
+    let rans_cumulative: Vec<_> = rans_cumulative.iter().map(u16::to_string).collect();
+    write!(
+        code_file,
+        "pub type RansRun = {rans_type};
+        pub const RANS_RUNS: &[RansRun] = &[{}];
+        pub const RANS_CUMULATIVE: &[u16] = &[{}];",
+        rans_runs.join(","),
+        rans_cumulative.join(",")
+    )
+    .unwrap();
 
     write!(code_file, "pub const PALETTE: [u32; {}] = [", PALETTE.len()).unwrap();
     for color in PALETTE {
@@ -249,10 +431,742 @@ fn main() {
 
     write!(code_file, "}}").unwrap();
 
-    assert!(Command::new("./audio.py").status().unwrap().success());
+    assert!(Command::new("./audio.py")
+        .env("TICK_RATE", TICK_RATE.to_string())
+        .env("AV_OFFSET", AUDIO_OFFSET.to_string())
+        .env("LOOP_MUSIC", (LOOP_MUSIC as u8).to_string())
+        .status()
+        .unwrap()
+        .success());
+
+    let audio_size = ["pulse_one", "pulse_two", "triangle", "noise"]
+        .iter()
+        .map(|name| {
+            let path = format!("{}/{name}.bin", env::var("OUT_DIR").unwrap());
+            fs::metadata(path).unwrap().len() as usize
+        })
+        .sum();
+    check_memory_budget(&[
+        ("movie.bin", movie.bytes()),
+        ("run code lengths", run_length_counts.len() * 2),
+        ("runs-data.bin", runs_data.bytes()),
+        ("runs.bin", runs.len()),
+        ("rANS table", rans.as_ref().map_or(0, RansCode::table_bytes)),
+        (
+            "split run tables",
+            split_runs
+                .as_ref()
+                .map_or(0, SegmentedRunCodes::table_bytes),
+        ),
+        (
+            "reference frames",
+            reference_slots * (RESCALE_WIDTH * RESCALE_HEIGHT * BPP).div_ceil(8) as usize,
+        ),
+        ("outline edges", outline_edge_bytes),
+        ("audio streams", audio_size),
+        ("cart data (estimate)", CART_DATA_ESTIMATE),
+    ]);
+}
+
+/// Writes the tables for runs with separately coded kinds and lengths, and the
+/// segments they are used for. They are empty when runs use joint codes, as the
+/// cart still refers to them.
+fn write_split_run_tables(
+    to: &mut impl Write,
+    codes: Option<&SegmentedRunCodes>,
+) -> std::io::Result<()> {
+    let table = |code: Option<&HuffmanCode<u8>>| {
+        let mut counts = code.map_or(vec![], |code| code.length_counts().to_vec());
+        counts.resize(MAX_CODE_LENGTH, 0);
+        let values = code.map_or(&[][..], |code| code.values());
+        (counts, values.to_vec())
+    };
+    let layout = match codes {
+        Some(_) => "Split",
+        None => "Joint",
+    };
+    let segments = codes.map_or(vec![(0, 0)], |codes| codes.segments.clone());
+    let codes = codes.map_or(vec![None], |codes| codes.codes.iter().map(Some).collect());
+    write!(
+        to,
+        "pub const RUN_LAYOUT: super::RunLayout = super::RunLayout::{layout};
+        pub const RUN_SEGMENTS: &[(u32, u8)] = &{segments:?};
+        pub const RUN_TABLES: usize = {};",
+        codes.len()
+    )?;
+
+    let kinds: Vec<_> = codes
+        .iter()
+        .map(|code| table(code.map(|code| &code.kinds)))
+        .collect();
+    write!(
+        to,
+        "pub const RUN_KIND_COUNTS: [[u16; MAX_CODE_LENGTH]; RUN_TABLES] = ["
+    )?;
+    for (counts, _) in &kinds {
+        write!(to, "{counts:?},")?;
+    }
+    write!(to, "]; pub const RUN_KINDS: [&[u8]; RUN_TABLES] = [")?;
+    for (_, values) in &kinds {
+        write!(to, "&{values:?},")?;
+    }
+    write!(to, "];")?;
+
+    let buckets: Vec<Vec<_>> = codes
+        .iter()
+        .map(|code| {
+            (0..PALETTE.len() + 1)
+                .map(|kind| table(code.and_then(|code| code.buckets[kind].as_ref())))
+                .collect()
+        })
+        .collect();
+    write!(
+        to,
+        "pub const RUN_BUCKET_COUNTS: [[[u16; MAX_CODE_LENGTH]; {}]; RUN_TABLES] = [",
+        PALETTE.len() + 1
+    )?;
+    for tables in &buckets {
+        write!(to, "[")?;
+        for (counts, _) in tables {
+            write!(to, "{counts:?},")?;
+        }
+        write!(to, "],")?;
+    }
+    write!(
+        to,
+        "]; pub const RUN_BUCKETS: [[&[u8]; {}]; RUN_TABLES] = [",
+        PALETTE.len() + 1
+    )?;
+    for tables in &buckets {
+        write!(to, "[")?;
+        for (_, values) in tables {
+            write!(to, "&{values:?},")?;
+        }
+        write!(to, "],")?;
+    }
+    write!(to, "];")
+}
+
+/// Checks that the cart's data fits in WASM-4's memory, so that an oversized video
+/// fails here with an explanation instead of with a linker error.
+fn check_memory_budget(assets: &[(&str, usize)]) {
+    println!("cargo:rerun-if-changed=.cargo/config.toml");
+    let config = fs::read_to_string(".cargo/config.toml").unwrap();
+    let linker_arg = |name: &str| {
+        config
+            .split(name)
+            .nth(1)
+            .and_then(|rest| rest.split(|c: char| !c.is_ascii_digit()).next())
+            .and_then(|value| value.parse::<usize>().ok())
+    };
+    let memory = linker_arg("--initial-memory=").unwrap_or(WASM4_MEMORY);
+    // The stack is placed at the start of memory and overlaps WASM-4's reserved
+    // region, so whichever is larger is unavailable to the cart's data.
+    let reserved = linker_arg("-zstack-size=").unwrap_or(0).max(WASM4_RESERVED);
+    let available = memory - reserved;
+    let total: usize = assets.iter().map(|&(_, size)| size).sum();
+
+    let mut report = format!(
+        "{total} of {available} bytes used ({memory} bytes of memory, {reserved} reserved):\n"
+    );
+    for &(name, size) in assets {
+        report += &format!(
+            "  {name:<22}{size:>6} ({:.1}%)\n",
+            size as f64 * 100.0 / available as f64
+        );
+    }
+    for line in report.lines() {
+        println!("cargo:warning={line}");
+    }
+
+    if total > available {
+        let over = total - available;
+        let (largest, _) = assets.iter().max_by_key(|&&(_, size)| size).unwrap();
+        let video: usize = assets
+            .iter()
+            .filter(|(name, _)| name.ends_with(".bin"))
+            .map(|&(_, size)| size)
+            .sum();
+        let hint = match video > over {
+            true => format!(
+                "Set TARGET_SIZE in build/main.rs to at most {} or reduce",
+                video - over
+            ),
+            false => "Reduce".to_owned(),
+        };
+        panic!(
+            "cart data is {over} bytes over budget, the largest asset is {largest}.\n{report}\
+            {hint} the video's resolution, framerate or length."
+        );
+    }
+}
+
+struct Movie {
+    movie: BitVec,
+    /// Number of run codewords of each length, from 1 bit. All zero unless runs use
+    /// a joint Huffman code, as the cart's table has a fixed size.
+    run_length_counts: Vec<u16>,
+    runs_data: BitVec,
+    run_value_bits: u32,
+    /// Range or rANS coded runs, when not using Huffman codes.
+    runs: Vec<u8>,
+    rans: Option<RansCode>,
+    runs_huffman: HuffmanCode<Run>,
+    /// Separate codes for the kind and length of runs, when that is smaller.
+    split_runs: Option<SegmentedRunCodes>,
+    /// Bytes the runs take with joint and with split codes, and the number of
+    /// segments of the split codes, when Huffman coded.
+    run_code_sizes: Option<(usize, usize, usize)>,
+    /// How the runs are coded: `RUN_CODER`, or Huffman when rANS would be bigger.
+    run_coder: RunCoder,
+    order_huffman: HuffmanCode<usize>,
+    num_rects_huffman: HuffmanCode<usize>,
+    quadtree_huffman: HuffmanCode<u8>,
+    turn_huffman: HuffmanCode<u8>,
+    /// Size of the cart's cache of reference frames.
+    reference_slots: usize,
+    reference_huffman: HuffmanCode<usize>,
+    /// Size of the cart's outline edge flags, which only exist when some frame is
+    /// drawn from outlines.
+    outline_edge_bytes: usize,
+}
+
+impl Movie {
+    fn size(&self) -> usize {
+        self.movie.bytes()
+            + self.run_length_counts.len() * 2
+            + self.runs_data.bytes()
+            + self.runs.len()
+            + self.rans.as_ref().map_or(0, RansCode::table_bytes)
+            + self
+                .split_runs
+                .as_ref()
+                .map_or(0, SegmentedRunCodes::table_bytes)
+    }
+
+    /// Length of the codeword for `run` in `frame`, or in the code for the whole movie.
+    fn run_code_length(&self, frame: Option<usize>, run: &Run) -> Option<usize> {
+        match (&self.split_runs, frame) {
+            (Some(codes), Some(frame)) => codes.code(frame).code_length(run),
+            (Some(codes), None) => codes.codes[0].code_length(run),
+            (None, _) => self.runs_huffman.code_length(run),
+        }
+    }
+
+    /// Number of bits `encoded` would take in the movie stream as `frame` with this
+    /// movie's codes. Runs that aren't Huffman coded are estimated by their Huffman
+    /// code lengths.
+    fn frame_bits(&self, frame: usize, encoded: &EncodedFrame) -> FrameBits {
+        let code_length = |length: Option<usize>| length.unwrap_or(UNSEEN_SYMBOL_BITS);
+        let duration = match encoded.duration {
+            1 => 0,
+            duration => {
+                code_length(self.num_rects_huffman.code_length(&DURATION_FRAME))
+                    + int_bits(duration)
+            }
+        };
+        let mut bits = FrameBits {
+            num_rects: code_length(self.num_rects_huffman.code_length(&encoded.num_rects())),
+            duration,
+            ..FrameBits::default()
+        };
+        if !encoded.reference.is_empty() {
+            bits.reference = code_length(self.num_rects_huffman.code_length(&REFERENCE_FRAME))
+                + code_length(
+                    self.reference_huffman
+                        .code_length(&encoded.reference.symbol(self.reference_slots)),
+                );
+        }
+        match &encoded.coding {
+            FrameCoding::Rects(_) => {}
+            FrameCoding::Quadtree(nodes) => {
+                for node in nodes {
+                    bits.quadtree += code_length(self.quadtree_huffman.code_length(node));
+                }
+            }
+            FrameCoding::Outlines(outlines) => {
+                let mut other = BitVec::new();
+                outlines.encode(&mut other, |_, turn| {
+                    bits.outlines += code_length(self.turn_huffman.code_length(&turn));
+                });
+                bits.outlines += other.len();
+            }
+        }
+        self.add_rect_bits(frame, encoded.rects(), &mut bits);
+        bits
+    }
+
+    /// Adds the bits of `rects`, in the order they are in the stream, to `bits`.
+    fn add_rect_bits<'a>(
+        &self,
+        frame: usize,
+        rects: impl IntoIterator<Item = &'a EncodedRect>,
+        bits: &mut FrameBits,
+    ) {
+        let mut last_index = -1;
+        for enc_rect in rects {
+            let rect = enc_rect.rect;
+            let i = rect.y * RESCALE_WIDTH + rect.x;
+            let br = (rect.y + rect.h - 1) * RESCALE_WIDTH + rect.x + rect.w;
+            bits.positions += int_bits((i as i32 - last_index) as u32) + int_bits(br - i);
+            last_index = i as i32;
+            self.add_rect_content_bits(frame, enc_rect, bits);
+        }
+    }
+
+    /// Adds the bits of a rect after its position, its header and runs, to `bits`.
+    fn add_rect_content_bits(&self, frame: usize, enc_rect: &EncodedRect, bits: &mut FrameBits) {
+        let code_length = |length: Option<usize>| length.unwrap_or(UNSEEN_SYMBOL_BITS);
+        if enc_rect.has_header() {
+            bits.orders += code_length(self.order_huffman.code_length(&enc_rect.header()));
+        }
+        if let Some((dx, dy)) = enc_rect.motion {
+            bits.orders += signed_bits(dx) + signed_bits(dy);
+        }
+        for run in &enc_rect.runs {
+            bits.runs += code_length(self.run_code_length(Some(frame), run));
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct FrameBits {
+    num_rects: usize,
+    positions: usize,
+    orders: usize,
+    runs: usize,
+    quadtree: usize,
+    outlines: usize,
+    reference: usize,
+    duration: usize,
+}
+
+impl FrameBits {
+    fn total(self) -> usize {
+        self.num_rects
+            + self.positions
+            + self.orders
+            + self.runs
+            + self.quadtree
+            + self.outlines
+            + self.reference
+            + self.duration
+    }
+}
+
+fn int_bits(v: u32) -> usize {
+    let mut bits = BitVec::new();
+    bits.write_int(v);
+    bits.len()
+}
+
+fn signed_bits(v: i32) -> usize {
+    let mut bits = BitVec::new();
+    bits.write_signed(v);
+    bits.len()
+}
+
+fn encode_movie(data: &[EncodedFrame]) -> Movie {
+    let mut run_freq = HashMap::new();
+    let mut headers = HashMap::new();
+    let mut num_rects = HashMap::new();
+    let mut references = HashMap::new();
+    let mut biggest_run = 0;
+    let reference_slots = data
+        .iter()
+        .filter_map(|encoded| encoded.reference.store)
+        .max()
+        .map_or(0, |slot| slot + 1);
+    for encoded in data {
+        if encoded.duration != 1 {
+            *num_rects.entry(DURATION_FRAME).or_default() += 1;
+        }
+        *num_rects.entry(encoded.num_rects()).or_default() += 1;
+        if !encoded.reference.is_empty() {
+            *num_rects.entry(REFERENCE_FRAME).or_default() += 1;
+            *references
+                .entry(encoded.reference.symbol(reference_slots))
+                .or_default() += 1;
+        }
+        for enc_rect in encoded.rects() {
+            if enc_rect.has_header() {
+                *headers.entry(enc_rect.header()).or_default() += 1;
+            }
+            for &run in &enc_rect.runs {
+                *run_freq.entry(run).or_default() += 1;
+                let encoded = run.kind as u32 + run.length * (PALETTE.len() as u32 + 1);
+                if encoded > biggest_run {
+                    biggest_run = encoded;
+                }
+            }
+        }
+    }
+
+    let mut kind_freq = [0; 5];
+    for run in run_freq.keys() {
+        kind_freq[run.kind as usize] += 1;
+    }
+
+    let runs_huffman = HuffmanCode::new(
+        run_freq.iter().map(|(&run, &count)| (run, count)),
+        MAX_CODE_LENGTH,
+    );
+
+    let run_value_bits = (biggest_run + 1).next_power_of_two().trailing_zeros();
+    let mut run_length_counts = vec![0; MAX_CODE_LENGTH];
+    let mut runs_data = BitVec::new();
+    let mut runs = vec![];
+    let mut rans = None;
+    let mut split_runs = None;
+    let mut run_code_sizes = None;
+    match RUN_CODER {
+        RunCoder::Huffman | RunCoder::Rans => {
+            let split = SegmentedRunCodes::new(data);
+            let joint_bits: usize = run_freq
+                .iter()
+                .map(|(run, &count)| count as usize * runs_huffman.code_length(run).unwrap())
+                .sum();
+            // The cart's table of run codeword counts is there either way.
+            let joint_size = joint_bits / 8
+                + (runs_huffman.values().len() * run_value_bits as usize).div_ceil(8);
+            let split_size = split.bits(data) / 8 + split.table_bytes();
+            run_code_sizes = Some((joint_size, split_size, split.segments.len()));
+            // rANS needs its table in the cart, so Huffman codes may still be smaller.
+            let rans_runs = (RUN_CODER == RunCoder::Rans)
+                .then(|| {
+                    let code = RansCode::new(&run_freq);
+                    let runs = code.encode_runs(data);
+                    (code, runs)
+                })
+                .filter(|(code, runs)| {
+                    runs.len() + code.table_bytes() < joint_size.min(split_size)
+                });
+            if let Some((code, rans_runs)) = rans_runs {
+                runs = rans_runs;
+                rans = Some(code);
+            } else if split_size < joint_size {
+                split_runs = Some(split);
+            } else {
+                run_length_counts = runs_huffman.length_counts().to_vec();
+                run_length_counts.resize(MAX_CODE_LENGTH, 0);
+                for run in runs_huffman.values() {
+                    runs_data.write_bits(
+                        run.kind as u32 + run.length * (PALETTE.len() as u32 + 1),
+                        run_value_bits,
+                    );
+                }
+            }
+        }
+        RunCoder::Arithmetic => runs = arithmetic::encode_runs(data),
+    }
+    let run_coder = match (RUN_CODER, &rans) {
+        (RunCoder::Rans, None) => RunCoder::Huffman,
+        (coder, _) => coder,
+    };
+
+    let order_huffman = HuffmanCode::new(headers, MAX_CODE_LENGTH);
+
+    let num_rects_huffman = HuffmanCode::new(num_rects, MAX_CODE_LENGTH);
+
+    let quadtree_huffman = quadtree::node_code(data);
+    let turn_huffman = outline::turn_code(data);
+    let reference_huffman = HuffmanCode::new(references, MAX_CODE_LENGTH);
+
+    let uses_outlines = data
+        .iter()
+        .any(|encoded| matches!(encoded.coding, FrameCoding::Outlines(_)));
+    let outline_edge_bytes = if uses_outlines {
+        (RESCALE_WIDTH * RESCALE_HEIGHT).div_ceil(8) as usize
+    } else {
+        0
+    };
+
+    let mut movie = BitVec::new();
+    for (frame, encoded) in data.iter().enumerate() {
+        if encoded.duration != 1 {
+            num_rects_huffman.encode_value(&mut movie, &DURATION_FRAME);
+            movie.write_int(encoded.duration);
+        }
+        if !encoded.reference.is_empty() {
+            num_rects_huffman.encode_value(&mut movie, &REFERENCE_FRAME);
+            reference_huffman.encode_value(&mut movie, &encoded.reference.symbol(reference_slots));
+        }
+        num_rects_huffman.encode_value(&mut movie, &encoded.num_rects());
+        match &encoded.coding {
+            FrameCoding::Rects(_) => {}
+            FrameCoding::Quadtree(nodes) => {
+                for node in nodes {
+                    quadtree_huffman.encode_value(&mut movie, node);
+                }
+            }
+            FrameCoding::Outlines(outlines) => {
+                outlines.encode(&mut movie, |to, turn| turn_huffman.encode_value(to, &turn));
+            }
+        }
+        let mut last_index = -1;
+        for enc_rect in encoded.rects() {
+            let rect = enc_rect.rect;
+            let i = rect.y * RESCALE_WIDTH + rect.x;
+            let br = (rect.y + rect.h - 1) * RESCALE_WIDTH + rect.x + rect.w;
+            movie.write_int((i as i32 - last_index) as u32);
+            movie.write_int(br - i);
+            last_index = i as i32;
+
+            if enc_rect.has_header() {
+                order_huffman.encode_value(&mut movie, &enc_rect.header());
+            }
+            if let Some((dx, dy)) = enc_rect.motion {
+                movie.write_signed(dx);
+                movie.write_signed(dy);
+            }
+            if run_coder == RunCoder::Huffman {
+                for run in &enc_rect.runs {
+                    match &split_runs {
+                        Some(codes) => codes.code(frame).encode(&mut movie, run),
+                        None => runs_huffman.encode_value(&mut movie, run),
+                    }
+                }
+            }
+        }
+    }
+
+    Movie {
+        movie,
+        run_length_counts,
+        runs_data,
+        run_value_bits,
+        runs,
+        rans,
+        runs_huffman,
+        split_runs,
+        run_code_sizes,
+        run_coder,
+        order_huffman,
+        num_rects_huffman,
+        quadtree_huffman,
+        turn_huffman,
+        reference_slots,
+        reference_huffman,
+        outline_edge_bytes,
+    }
 }
 
-fn encode_frame(curr: &GrayImage, prev: &GrayImage) -> Vec<(Rect, usize, Vec<Run>)> {
+/// Drops frames that change fewer pixels from the last frame kept than the lowest
+/// threshold that leaves at most `max_frames`. Returns the frames kept, after the blank
+/// first one, and how long each of them is shown for.
+fn drop_frames(images: Vec<GrayImage>, max_frames: usize) -> (Vec<GrayImage>, Vec<u32>) {
+    // Fewer frames are kept as the threshold grows, and none after the first one once
+    // it is over the number of pixels.
+    let (mut low, mut high) = (1, RESCALE_WIDTH * RESCALE_HEIGHT + 1);
+    while low < high {
+        let mid = (low + high) / 2;
+        if kept_frames(&images, mid).len() <= max_frames {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    let kept = kept_frames(&images, low);
+    let durations = kept.iter().map(|&(_, duration)| duration).collect();
+    let mut images: Vec<_> = images.into_iter().map(Some).collect();
+    let mut frames = vec![images[0].take().unwrap()];
+    frames.extend(kept.iter().map(|&(frame, _)| images[frame].take().unwrap()));
+    (frames, durations)
+}
+
+/// The frames after the blank first one that change at least `min_changes` pixels from
+/// the last frame kept, and how long each is shown for. The first is always kept.
+fn kept_frames(images: &[GrayImage], min_changes: u32) -> Vec<(usize, u32)> {
+    let mut kept: Vec<(usize, u32)> = vec![];
+    for (frame, image) in images.iter().enumerate().skip(1) {
+        match kept.last_mut() {
+            Some((last, duration))
+                if references::differences(&images[*last], image) < min_changes =>
+            {
+                *duration += 1
+            }
+            _ => kept.push((frame, 1)),
+        }
+    }
+    kept
+}
+
+/// Codes every frame against the one before it, each as rects or as a quadtree.
+/// The first pass makes its choices by counting runs, later ones by bits under the
+/// codes built from the pass before, for as long as that shrinks the movie. When the
+/// first pass doesn't fit in `budget` bytes, it is returned as it is, since a lossy
+/// encoding replaces it anyway.
+fn encode_frames(
+    images: &[GrayImage],
+    durations: &[u32],
+    budget: Option<usize>,
+) -> Vec<EncodedFrame> {
+    let plan = ReferencePlan::new(images);
+    let mut caches = vec![MotionCache::new(); images.len() - 1];
+    let mut data = encode_pass(images, durations, &plan, None, &mut caches);
+    let mut movie = encode_movie(&data);
+    println!("cargo:warning=Pass 1: {} bytes", movie.size());
+    if budget.is_some_and(|budget| movie.size() > budget) {
+        return data;
+    }
+    for pass in 2..=MAX_PASSES {
+        let next = encode_pass(images, durations, &plan, Some(&movie), &mut caches);
+        let next_movie = encode_movie(&next);
+        let gain = movie.size() as isize - next_movie.size() as isize;
+        println!(
+            "cargo:warning=Pass {pass}: {} bytes, {gain} smaller",
+            next_movie.size()
+        );
+        if gain <= 0 {
+            break;
+        }
+        data = next;
+        movie = next_movie;
+    }
+    data
+}
+
+/// Codes each frame as rects and as a quadtree, against the previous frame and
+/// against the reference the plan has for it, and as outlines. `caches` has each
+/// frame's motion cache.
+fn encode_pass(
+    images: &[GrayImage],
+    durations: &[u32],
+    plan: &ReferencePlan,
+    movie: Option<&Movie>,
+    caches: &mut [MotionCache],
+) -> Vec<EncodedFrame> {
+    let candidates: Vec<_> = images
+        .par_windows(2)
+        .zip(caches)
+        .enumerate()
+        .map(|(frame, (v, cache))| {
+            let pricing = match movie {
+                Some(movie) => Pricing::Bits(movie, frame),
+                None => Pricing::Runs,
+            };
+            let store = plan.stores[frame];
+            let mut bases = vec![(None, &v[0])];
+            if let Some((slot, image)) = plan.restores[frame] {
+                bases.push((Some(slot), &images[image]));
+            }
+            let frame_coding = |restore, coding| EncodedFrame {
+                duration: durations[frame],
+                reference: ReferenceCommand { restore, store },
+                coding,
+            };
+            let mut codings: Vec<_> = bases
+                .into_iter()
+                .flat_map(|(restore, base)| {
+                    [
+                        encode_frame(&v[1], base, pricing, cache),
+                        encode_quadtree(&v[1], base),
+                    ]
+                    .map(|coding| frame_coding(restore, coding))
+                })
+                .collect();
+            codings.push(frame_coding(None, encode_outlines(&v[1])));
+            codings
+        })
+        .collect();
+    choose_codings(&candidates)
+}
+
+/// Picks the smaller coding of each frame. Sizes depend on the codes built from
+/// every frame, so the codings are first picked by their number of symbols and
+/// then repeatedly by their size under the codes of the last pick. Quadtree nodes
+/// and outline turns are priced with codes over every frame's quadtree and outlines,
+/// so that frames can switch to them when none were picked yet.
+fn choose_codings(candidates: &[Vec<EncodedFrame>]) -> Vec<EncodedFrame> {
+    let whole_frames: Vec<_> = candidates
+        .iter()
+        .flatten()
+        .filter(|encoded| !matches!(encoded.coding, FrameCoding::Rects(_)))
+        .cloned()
+        .collect();
+    let symbols = |encoded: &EncodedFrame| match &encoded.coding {
+        FrameCoding::Rects(rects) => rects.iter().map(|r| r.runs.len() + 2).sum(),
+        FrameCoding::Quadtree(nodes) => nodes.len(),
+        FrameCoding::Outlines(outlines) => outlines
+            .contours()
+            .map(|contour| contour.turns.len() + 2)
+            .sum(),
+    };
+    let mut data: Vec<_> = candidates
+        .iter()
+        .map(|codings| codings.iter().min_by_key(|&c| symbols(c)).unwrap().clone())
+        .collect();
+    for _ in 0..CODING_PASSES {
+        let movie = Movie {
+            quadtree_huffman: quadtree::node_code(&whole_frames),
+            turn_huffman: outline::turn_code(&whole_frames),
+            ..encode_movie(&data)
+        };
+        data = candidates
+            .iter()
+            .enumerate()
+            .map(|(frame, codings)| {
+                codings
+                    .iter()
+                    .min_by_key(|&c| movie.frame_bits(frame, c).total())
+                    .unwrap()
+                    .clone()
+            })
+            .collect();
+    }
+    data
+}
+
+/// How `encode_frame` compares ways of splitting a frame into rects.
+#[derive(Clone, Copy)]
+enum Pricing<'a> {
+    /// By number of runs, with each rect and motion offset costing as much as two,
+    /// for when there are no codes yet.
+    Runs,
+    /// By bits under a movie's codes, as the given frame.
+    Bits(&'a Movie, usize),
+}
+
+impl Pricing<'_> {
+    /// Cost of a rect besides its position, for choosing between ways of coding it.
+    fn rect_cost(self, enc_rect: &EncodedRect) -> usize {
+        match self {
+            Pricing::Runs => enc_rect.runs.len() + 2 * enc_rect.motion.is_some() as usize,
+            Pricing::Bits(movie, frame) => {
+                let mut bits = FrameBits::default();
+                movie.add_rect_content_bits(frame, enc_rect, &mut bits);
+                bits.total()
+            }
+        }
+    }
+
+    /// Cost of a frame's rects.
+    fn cost(self, rects: &[EncodedRect]) -> usize {
+        match self {
+            Pricing::Runs => rects.iter().map(|r| self.rect_cost(r) + 2).sum(),
+            Pricing::Bits(movie, frame) => {
+                let mut sorted: Vec<_> = rects.iter().collect();
+                sorted.sort_by_key(|r| (r.rect.y, r.rect.x));
+                let mut bits = FrameBits {
+                    num_rects: movie
+                        .num_rects_huffman
+                        .code_length(&rects.len())
+                        .unwrap_or(UNSEEN_SYMBOL_BITS),
+                    ..FrameBits::default()
+                };
+                movie.add_rect_bits(frame, sorted, &mut bits);
+                bits.total()
+            }
+        }
+    }
+}
+
+fn encode_frame(
+    curr: &GrayImage,
+    prev: &GrayImage,
+    pricing: Pricing,
+    cache: &mut MotionCache,
+) -> FrameCoding {
     let mut rects: Vec<_> = bounding_rect(
         curr,
         prev,
@@ -264,88 +1178,187 @@ fn encode_frame(curr: &GrayImage, prev: &GrayImage) -> Vec<(Rect, usize, Vec<Run
         },
     )
     .into_iter()
-    .map(|rect| encode_rect(curr, prev, rect))
+    .map(|rect| encode_rect(curr, prev, rect, None, pricing))
     .collect();
 
-    'split: loop {
-        for enc_rect in &mut rects {
-            'next: for x in enc_rect.0.xs().skip(1) {
-                for y in enc_rect.0.ys() {
-                    let left_is_diff = curr.get_pixel(x - 1, y) != prev.get_pixel(x - 1, y);
-                    let right_is_diff = curr.get_pixel(x, y) != prev.get_pixel(x, y);
-                    if left_is_diff && right_is_diff {
-                        continue 'next;
-                    }
-                }
-
-                let r1 = Rect {
-                    w: x - enc_rect.0.x,
-                    ..enc_rect.0
-                };
-                let r1 = encode_rect(curr, prev, bounding_rect(curr, prev, r1).unwrap());
-
-                let r2 = Rect {
-                    x,
-                    w: enc_rect.0.w - (x - enc_rect.0.x),
-                    ..enc_rect.0
-                };
-                let r2 = encode_rect(curr, prev, bounding_rect(curr, prev, r2).unwrap());
+    // Split each rect at whichever cut makes the frame cheapest, until no cut helps.
+    let mut i = 0;
+    while i < rects.len() {
+        let cost = pricing.cost(&rects);
+        let best = cuts(curr, prev, rects[i].rect)
+            .map(|(r1, r2)| {
+                let mut split = rects.clone();
+                split[i] = encode_rect(curr, prev, r1, None, pricing);
+                split.push(encode_rect(curr, prev, r2, None, pricing));
+                (pricing.cost(&split), split)
+            })
+            .min_by_key(|&(cost, _)| cost);
+        match best {
+            Some((split_cost, split)) if split_cost < cost => rects = split,
+            _ => i += 1,
+        }
+    }
 
-                if r1.2.len() + r2.2.len() + 2 < enc_rect.2.len() {
-                    *enc_rect = r1;
-                    rects.push(r2);
-                    continue 'split;
-                }
-            }
+    rects.sort_by_key(|r| (r.rect.y, r.rect.x));
+    compensate_motion(curr, prev, &mut rects, pricing, cache);
+    FrameCoding::Rects(rects)
+}
 
-            'next: for y in enc_rect.0.ys().skip(1) {
-                for x in enc_rect.0.xs() {
-                    let up_is_diff = curr.get_pixel(x, y - 1) != prev.get_pixel(x, y - 1);
-                    let down_is_diff = curr.get_pixel(x, y) != prev.get_pixel(x, y);
-                    if up_is_diff && down_is_diff {
-                        continue 'next;
+/// Predicts rects from a shifted region of the previous frame where that is cheaper.
+/// The cart copies the region from the frame as decoded so far, so the rects before
+/// it in the frame are already applied. Only the offsets that predict the fewest
+/// pixels wrong, and fewer than no offset, are coded.
+fn compensate_motion(
+    curr: &GrayImage,
+    prev: &GrayImage,
+    rects: &mut [EncodedRect],
+    pricing: Pricing,
+    cache: &mut MotionCache,
+) {
+    let mut decoded = prev.clone();
+    for enc_rect in rects {
+        let rect = enc_rect.rect;
+        if enc_rect.has_header() {
+            let wrong = |(dx, dy): (i32, i32)| {
+                scanline(rect)
+                    .filter(|&(x, y)| {
+                        let source =
+                            decoded.get_pixel((x as i32 + dx) as u32, (y as i32 + dy) as u32);
+                        source != curr.get_pixel(x, y)
+                    })
+                    .count()
+            };
+            let unshifted = wrong((0, 0));
+            let mut offsets: Vec<_> = (-MOTION_RANGE..=MOTION_RANGE)
+                .flat_map(|dy| (-MOTION_RANGE..=MOTION_RANGE).map(move |dx| (dx, dy)))
+                .filter(|&(dx, dy)| {
+                    (dx, dy) != (0, 0)
+                        && rect.x as i32 + dx >= 0
+                        && rect.y as i32 + dy >= 0
+                        && rect.x as i32 + rect.w as i32 + dx <= curr.width() as i32
+                        && rect.y as i32 + rect.h as i32 + dy <= curr.height() as i32
+                })
+                .map(|offset| (wrong(offset), offset))
+                .filter(|&(wrong, _)| wrong < unshifted)
+                .collect();
+            offsets.sort_unstable();
+            let best = offsets
+                .into_iter()
+                .take(MOTION_CANDIDATES)
+                .filter_map(|(_, (dx, dy))| {
+                    let mut predicted = decoded.clone();
+                    for (x, y) in scanline(rect) {
+                        let source =
+                            decoded.get_pixel((x as i32 + dx) as u32, (y as i32 + dy) as u32);
+                        predicted.put_pixel(x, y, *source);
                     }
-                }
-
-                let r1 = Rect {
-                    h: y - enc_rect.0.y,
-                    ..enc_rect.0
-                };
-                let r1 = encode_rect(curr, prev, bounding_rect(curr, prev, r1).unwrap());
-
-                let r2 = Rect {
-                    y,
-                    h: enc_rect.0.h - (y - enc_rect.0.y),
-                    ..enc_rect.0
-                };
-                let r2 = encode_rect(curr, prev, bounding_rect(curr, prev, r2).unwrap());
-
-                if r1.2.len() + r2.2.len() + 2 < enc_rect.2.len() {
-                    *enc_rect = r1;
-                    rects.push(r2);
-                    continue 'split;
+                    let sources = scanline(rect)
+                        .map(|(x, y)| predicted.get_pixel(x, y)[0])
+                        .collect();
+                    cache
+                        .entry((rect, (dx, dy), sources))
+                        .or_insert_with(|| rect_codings(curr, &predicted, rect, Some((dx, dy))))
+                        .iter()
+                        .min_by_key(|candidate| pricing.rect_cost(candidate))
+                        .cloned()
+                })
+                .min_by_key(|candidate| pricing.rect_cost(candidate));
+            if let Some(best) = best {
+                if pricing.rect_cost(&best) < pricing.rect_cost(enc_rect) {
+                    *enc_rect = best;
                 }
             }
         }
-        break;
+        for (x, y) in scanline(rect) {
+            decoded.put_pixel(x, y, *curr.get_pixel(x, y));
+        }
     }
+}
 
-    rects.sort_by_key(|(r, _, _)| (r.y, r.x));
-    rects
+/// The ways to split `rect` in two along a column or row that no changed pixel
+/// touches from both sides, with each half shrunk to its changed pixels.
+fn cuts<'a>(
+    curr: &'a GrayImage,
+    prev: &'a GrayImage,
+    rect: Rect,
+) -> impl Iterator<Item = (Rect, Rect)> + 'a {
+    let changed = |x, y| curr.get_pixel(x, y) != prev.get_pixel(x, y);
+    let columns = rect
+        .xs()
+        .skip(1)
+        .filter(move |&x| !rect.ys().any(|y| changed(x - 1, y) && changed(x, y)))
+        .map(move |x| {
+            let left = Rect {
+                w: x - rect.x,
+                ..rect
+            };
+            let right = Rect {
+                x,
+                w: rect.w - (x - rect.x),
+                ..rect
+            };
+            (left, right)
+        });
+    let rows = rect
+        .ys()
+        .skip(1)
+        .filter(move |&y| !rect.xs().any(|x| changed(x, y - 1) && changed(x, y)))
+        .map(move |y| {
+            let top = Rect {
+                h: y - rect.y,
+                ..rect
+            };
+            let bottom = Rect {
+                y,
+                h: rect.h - (y - rect.y),
+                ..rect
+            };
+            (top, bottom)
+        });
+    columns.chain(rows).map(move |(r1, r2)| {
+        (
+            bounding_rect(curr, prev, r1).unwrap(),
+            bounding_rect(curr, prev, r2).unwrap(),
+        )
+    })
 }
 
-fn encode_rect(curr: &GrayImage, prev: &GrayImage, rect: Rect) -> (Rect, usize, Vec<Run>) {
-    [
-        encode(value_sets(curr, prev, scanline(rect))),
-        encode(value_sets(curr, prev, transpose(scanline, rect))),
-        encode(value_sets(curr, prev, snake(rect))),
-        encode(value_sets(curr, prev, transpose(snake, rect))),
-    ]
-    .into_iter()
-    .enumerate()
-    .min_by_key(|(_, data)| data.len())
-    .map(|(order, data)| (rect, order, data))
-    .unwrap()
+/// Codes `rect` in the order and mode that `pricing` finds cheapest.
+fn encode_rect(
+    curr: &GrayImage,
+    prev: &GrayImage,
+    rect: Rect,
+    motion: Option<(i32, i32)>,
+    pricing: Pricing,
+) -> EncodedRect {
+    rect_codings(curr, prev, rect, motion)
+        .into_iter()
+        .min_by_key(|enc_rect| pricing.rect_cost(enc_rect))
+        .unwrap()
+}
+
+/// Codes `rect` in every order and mode it can be in.
+fn rect_codings(
+    curr: &GrayImage,
+    prev: &GrayImage,
+    rect: Rect,
+    motion: Option<(i32, i32)>,
+) -> Vec<EncodedRect> {
+    let (xors, orders) = match rect.has_header() {
+        true => (&[false, true][..], ORDERS),
+        false => (&[false][..], 1),
+    };
+    xors.iter()
+        .flat_map(|&xor| {
+            (0..orders).map(move |order| EncodedRect {
+                rect,
+                order,
+                motion,
+                xor,
+                runs: encode(value_sets(curr, prev, xor, visit(rect, order))),
+            })
+        })
+        .collect()
 }
 
 fn encode(mut value_sets: impl Iterator<Item = u8>) -> Vec<Run> {
@@ -378,12 +1391,18 @@ fn encode(mut value_sets: impl Iterator<Item = u8>) -> Vec<Run> {
 fn value_sets<'a>(
     current: &'a GrayImage,
     previous: &'a GrayImage,
+    xor: bool,
     order: impl Iterator<Item = (u32, u32)> + 'a,
 ) -> impl Iterator<Item = u8> + 'a {
     order.map(move |(x, y)| {
         let c = current.get_pixel(x, y).0[0];
         let p = previous.get_pixel(x, y).0[0];
-        1 << c | ((c == p) as u8) << UNCHANGED_BIT
+        // XORing with 0 leaves a pixel unchanged, like the unchanged kind.
+        let kind = match xor {
+            false => c,
+            true => c ^ p,
+        };
+        1 << kind | ((c == p) as u8) << UNCHANGED_BIT
     })
 }
 
@@ -391,28 +1410,13 @@ fn scanline(rect: Rect) -> impl Iterator<Item = (u32, u32)> {
     rect.ys().flat_map(move |y| rect.xs().map(move |x| (x, y)))
 }
 
-fn snake(rect: Rect) -> impl Iterator<Item = (u32, u32)> {
-    let mut going_back = true;
-    rect.ys().flat_map(move |y| {
-        going_back ^= true;
-        (0..rect.w).map(move |dx| match going_back {
-            false => (rect.x + dx, y),
-            true => (rect.x + rect.w - dx - 1, y),
-        })
-    })
-}
-
-fn transpose<I: Iterator<Item = (u32, u32)>>(
-    orderer: impl Fn(Rect) -> I,
-    rect: Rect,
-) -> impl Iterator<Item = (u32, u32)> {
-    orderer(Rect {
-        x: rect.y,
-        y: rect.x,
-        w: rect.h,
-        h: rect.w,
+/// The pixels of `rect` in `order`, as the cart's `next_xy` visits them.
+fn visit(rect: Rect, order: usize) -> impl Iterator<Item = (u32, u32)> {
+    let mut p = 0;
+    (0..rect.w * rect.h).map(move |_| {
+        let (x, y) = order::next_xy(&mut p, order as u32, rect.w, rect.h);
+        (rect.x + x, rect.y + y)
     })
-    .map(|(y, x)| (x, y))
 }
 
 fn bounding_rect(curr: &GrayImage, prev: &GrayImage, start: Rect) -> Option<Rect> {
@@ -442,3 +1446,282 @@ fn bounding_rect(curr: &GrayImage, prev: &GrayImage, start: Rect) -> Option<Rect
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame with the first `changed` pixels white.
+    fn frame(changed: u32) -> GrayImage {
+        GrayImage::from_fn(RESCALE_WIDTH, RESCALE_HEIGHT, |x, y| {
+            image::Luma([(y * RESCALE_WIDTH + x < changed) as u8])
+        })
+    }
+
+    /// A disc of white pixels.
+    fn disc(cx: i32, cy: i32, r: i32) -> GrayImage {
+        GrayImage::from_fn(RESCALE_WIDTH, RESCALE_HEIGHT, |x, y| {
+            let (dx, dy) = (x as i32 - cx, y as i32 - cy);
+            image::Luma([(dx * dx + dy * dy <= r * r) as u8])
+        })
+    }
+
+    /// Pixels white at random, a `percent` of the time.
+    fn noise(seed: u32, percent: u32) -> GrayImage {
+        GrayImage::from_fn(RESCALE_WIDTH, RESCALE_HEIGHT, |x, y| {
+            let hash = (y * RESCALE_WIDTH + x + seed * 7919).wrapping_mul(2654435761) >> 16;
+            image::Luma([(hash % 100 < percent) as u8])
+        })
+    }
+
+    /// A checkered square with its left edge at `left`.
+    fn checkers(left: u32) -> GrayImage {
+        GrayImage::from_fn(RESCALE_WIDTH, RESCALE_HEIGHT, |x, y| {
+            let inside = (left..left + 12).contains(&x) && (8..20).contains(&y);
+            image::Luma([(inside && (x - left + y).is_multiple_of(2)) as u8])
+        })
+    }
+
+    /// Frames and the frame before them, with moving edges, noise and shifted patterns.
+    fn frame_pairs() -> Vec<(GrayImage, GrayImage)> {
+        vec![
+            (disc(15, 12, 6), disc(17, 13, 6)),
+            (disc(20, 15, 9), disc(20, 15, 7)),
+            (frame(0), disc(10, 20, 5)),
+            (noise(1, 50), noise(2, 50)),
+            (noise(3, 10), noise(3, 12)),
+            (checkers(10), checkers(11)),
+            (checkers(14), checkers(12)),
+        ]
+    }
+
+    /// Draws `enc_rect` onto `screen` the way `decode_rect` in the cart does.
+    fn draw_rect(screen: &mut GrayImage, enc_rect: &EncodedRect) {
+        let Rect { x, y, w, h } = enc_rect.rect;
+        if let Some((dx, dy)) = enc_rect.motion {
+            // Like `copy_shifted`, in the direction that reads each pixel before it
+            // is overwritten.
+            for row in 0..h {
+                let row = if dy < 0 { h - 1 - row } else { row };
+                for column in 0..w {
+                    let column = if dy == 0 && dx < 0 {
+                        w - 1 - column
+                    } else {
+                        column
+                    };
+                    let (tx, ty) = (x + column, y + row);
+                    let source =
+                        *screen.get_pixel(tx.wrapping_add_signed(dx), ty.wrapping_add_signed(dy));
+                    screen.put_pixel(tx, ty, source);
+                }
+            }
+        }
+        let mut p = 0;
+        for run in &enc_rect.runs {
+            for _ in 0..run.length {
+                let (dx, dy) = order::next_xy(&mut p, enc_rect.order as u32, w, h);
+                if run.kind as usize != PALETTE.len() {
+                    let pixel = screen.get_pixel_mut(x + dx, y + dy);
+                    pixel[0] = match enc_rect.xor {
+                        true => pixel[0] ^ run.kind,
+                        false => run.kind,
+                    };
+                }
+            }
+        }
+        assert_eq!(p.min(w * h), w * h, "runs cover the rect");
+    }
+
+    /// Draws a frame coded as rects onto the frame before it.
+    fn draw_rects(prev: &GrayImage, rects: &[EncodedRect]) -> GrayImage {
+        let mut screen = prev.clone();
+        for enc_rect in rects {
+            draw_rect(&mut screen, enc_rect);
+        }
+        screen
+    }
+
+    #[test]
+    fn every_coding_draws_the_rect() {
+        for (prev, curr) in frame_pairs() {
+            let rect = bounding_rect(
+                &curr,
+                &prev,
+                Rect {
+                    x: 0,
+                    y: 0,
+                    w: RESCALE_WIDTH,
+                    h: RESCALE_HEIGHT,
+                },
+            )
+            .unwrap();
+            let codings = rect_codings(&curr, &prev, rect, None);
+            assert_eq!(codings.len(), 2 * ORDERS);
+            assert!(codings.iter().any(|enc_rect| enc_rect.xor));
+            for enc_rect in codings {
+                let mut screen = prev.clone();
+                draw_rect(&mut screen, &enc_rect);
+                assert!(
+                    screen == curr,
+                    "order {} xor {}",
+                    enc_rect.order,
+                    enc_rect.xor
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn frames_draw_back() {
+        let mut moved = false;
+        for (prev, curr) in frame_pairs() {
+            let FrameCoding::Rects(rects) =
+                encode_frame(&curr, &prev, Pricing::Runs, &mut MotionCache::new())
+            else {
+                unreachable!()
+            };
+            moved |= rects.iter().any(|r| r.motion.is_some());
+            assert!(draw_rects(&prev, &rects) == curr);
+        }
+        assert!(moved);
+    }
+
+    #[test]
+    fn motion_codings_draw_the_rect() {
+        for (prev, curr) in frame_pairs() {
+            let whole = Rect {
+                x: 0,
+                y: 0,
+                w: RESCALE_WIDTH,
+                h: RESCALE_HEIGHT,
+            };
+            let rect = bounding_rect(&curr, &prev, whole).unwrap();
+            for dy in -MOTION_RANGE..=MOTION_RANGE {
+                for dx in -MOTION_RANGE..=MOTION_RANGE {
+                    let (Some(x), Some(y)) =
+                        (rect.x.checked_add_signed(dx), rect.y.checked_add_signed(dy))
+                    else {
+                        continue;
+                    };
+                    if x + rect.w > RESCALE_WIDTH || y + rect.h > RESCALE_HEIGHT {
+                        continue;
+                    }
+                    // Predicted the way `compensate_motion` does, from a copy of the
+                    // frame before.
+                    let mut predicted = prev.clone();
+                    for (px, py) in scanline(rect) {
+                        let source = prev.get_pixel(px - rect.x + x, py - rect.y + y);
+                        predicted.put_pixel(px, py, *source);
+                    }
+                    for enc_rect in rect_codings(&curr, &predicted, rect, Some((dx, dy))) {
+                        let mut screen = prev.clone();
+                        draw_rect(&mut screen, &enc_rect);
+                        assert!(screen == curr, "offset {:?}", (dx, dy));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn orders_visit_every_pixel_once() {
+        for (w, h) in [
+            (1, 1),
+            (2, 2),
+            (5, 3),
+            (3, 5),
+            (8, 8),
+            (13, 6),
+            (1, 9),
+            (30, 40),
+        ] {
+            let rect = Rect { x: 3, y: 2, w, h };
+            for order in 0..ORDERS {
+                let mut pixels: Vec<_> = visit(rect, order).collect();
+                pixels.sort_unstable();
+                let mut all: Vec<_> = scanline(rect).collect();
+                all.sort_unstable();
+                assert_eq!(pixels, all, "order {order} over {w}x{h}");
+            }
+        }
+    }
+
+    #[test]
+    fn orders_over_small_rects() {
+        let visits = |order, w, h| visit(Rect { x: 0, y: 0, w, h }, order).collect::<Vec<_>>();
+        let columns = [(0, 0), (0, 1), (1, 0), (1, 1), (2, 0), (2, 1)];
+        assert_eq!(visits(1, 3, 2), columns);
+        let snake = [(0, 0), (1, 0), (2, 0), (2, 1), (1, 1), (0, 1)];
+        assert_eq!(visits(2, 3, 2), snake);
+        assert_eq!(visits(4, 2, 2), [(0, 0), (0, 1), (1, 1), (1, 0)]);
+        assert_eq!(visits(6, 2, 2), [(0, 0), (1, 0), (0, 1), (1, 1)]);
+        let zigzag = [(0, 0), (1, 0), (0, 1), (1, 1), (2, 0), (2, 1)];
+        assert_eq!(visits(8, 3, 2), zigzag);
+    }
+
+    #[test]
+    fn drop_frames_keeps_the_biggest_changes() {
+        // Alternating small and large changes, with repeats in between.
+        let changes = [0, 4, 4, 400, 400, 404, 800, 801, 801, 1200, 1198];
+        let images: Vec<_> = changes.iter().map(|&changed| frame(changed)).collect();
+        let (kept, durations) = drop_frames(images, 4);
+        // Every large change is kept, after the blank first frame.
+        let kept: Vec<_> = kept
+            .iter()
+            .map(|image| references::differences(image, &frame(0)))
+            .collect();
+        assert_eq!(kept, [0, 4, 400, 800, 1200]);
+        assert_eq!(durations, [2, 3, 3, 2]);
+    }
+
+    #[test]
+    fn merge_unchanged_lengthens_the_frame_before() {
+        let encoded = |duration, changed: bool| EncodedFrame {
+            duration,
+            reference: ReferenceCommand::default(),
+            coding: match changed {
+                true => FrameCoding::Quadtree(vec![0]),
+                false => FrameCoding::Rects(vec![]),
+            },
+        };
+        let data = vec![
+            encoded(1, false),
+            encoded(2, true),
+            encoded(1, false),
+            encoded(3, false),
+            encoded(1, true),
+        ];
+        let durations: Vec<_> = merge_unchanged(data)
+            .iter()
+            .map(|encoded| (encoded.duration, encoded.is_unchanged()))
+            .collect();
+        // The first frame has none before it to merge into.
+        assert_eq!(durations, [(1, true), (6, false), (1, false)]);
+    }
+
+    #[test]
+    fn motion_follows_a_moving_pattern() {
+        // A checkered square, which is many runs unless predicted from where it was.
+        let (prev, curr) = (checkers(10), checkers(11));
+        let mut cache = MotionCache::new();
+        let coded = |cache: &mut MotionCache| match encode_frame(&curr, &prev, Pricing::Runs, cache)
+        {
+            FrameCoding::Rects(rects) => rects
+                .into_iter()
+                .map(|r| (r.rect, r.order, r.motion, r.xor, r.runs))
+                .collect::<Vec<_>>(),
+            _ => unreachable!(),
+        };
+        let rects = coded(&mut cache);
+        assert!(rects.iter().any(|r| r.2 == Some((-1, 0))));
+        // Coding it again from the cache gives the same rects.
+        assert!(!cache.is_empty());
+        assert!(coded(&mut cache) == rects);
+    }
+
+    #[test]
+    fn drop_frames_always_drops_repeats() {
+        let images = vec![frame(0), frame(10), frame(10), frame(10), frame(20)];
+        assert_eq!(kept_frames(&images, 1), [(1, 3), (4, 1)]);
+    }
+}
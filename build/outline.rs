@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+
+use image::GrayImage;
+
+use crate::bitvec::BitVec;
+use crate::huffman::HuffmanCode;
+use crate::{EncodedFrame, FrameCoding, MAX_CODE_LENGTH, PALETTE, RESCALE_HEIGHT, RESCALE_WIDTH};
+
+/// Turn symbols, mirroring `decode_outlines` in the cart.
+const STRAIGHT: u8 = 0;
+const LEFT: u8 = 1;
+const RIGHT: u8 = 2;
+
+/// Steps along the pixel corner grid, each a quarter turn clockwise from the last.
+const DIRECTIONS: [(i32, i32); 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+
+/// A frame drawn from scratch: filled with a background color, then with each other
+/// color inside its outlines.
+#[derive(Clone)]
+pub struct Outlines {
+    pub background: u8,
+    /// For each other color, in palette order, the outlines of its regions.
+    pub layers: Vec<Vec<Contour>>,
+}
+
+/// A closed boundary between pixels, with the region on its right. It starts at the
+/// top left corner of a pixel going right, and ends back at that corner about to go
+/// right again.
+#[derive(Clone)]
+pub struct Contour {
+    pub start: (u32, u32),
+    /// The turn at each corner after the first step.
+    pub turns: Vec<u8>,
+}
+
+impl Outlines {
+    pub fn contours(&self) -> impl Iterator<Item = &Contour> {
+        self.layers.iter().flatten()
+    }
+
+    /// Writes everything but the turns, which are Huffman coded, calling `turn` for
+    /// each of them in stream order.
+    pub fn encode(&self, to: &mut BitVec, mut turn: impl FnMut(&mut BitVec, u8)) {
+        to.write_bits(self.background as u32, crate::BPP);
+        for contours in &self.layers {
+            to.write_int(contours.len() as u32 + 1);
+            let mut last_index = -1;
+            for contour in contours {
+                let (x, y) = contour.start;
+                let i = (y * RESCALE_WIDTH + x) as i32;
+                to.write_int((i - last_index) as u32);
+                last_index = i;
+                for &t in &contour.turns {
+                    turn(to, t);
+                }
+            }
+        }
+    }
+}
+
+/// Traces the outlines of every color but the most common one.
+pub fn encode_outlines(curr: &GrayImage) -> FrameCoding {
+    let mut counts = [0; PALETTE.len()];
+    for pixel in curr.pixels() {
+        counts[pixel.0[0] as usize] += 1;
+    }
+    let background = (0..PALETTE.len()).max_by_key(|&c| counts[c]).unwrap() as u8;
+    let layers = (0..PALETTE.len() as u8)
+        .filter(|&color| color != background)
+        .map(|color| trace(curr, color))
+        .collect();
+    FrameCoding::Outlines(Outlines { background, layers })
+}
+
+/// Huffman code for the turns of the outline frames in `data`.
+pub fn turn_code(data: &[EncodedFrame]) -> HuffmanCode<u8> {
+    let mut freq = HashMap::new();
+    for encoded in data {
+        if let FrameCoding::Outlines(outlines) = &encoded.coding {
+            for contour in outlines.contours() {
+                for &turn in &contour.turns {
+                    *freq.entry(turn).or_default() += 1;
+                }
+            }
+        }
+    }
+    HuffmanCode::new(freq, MAX_CODE_LENGTH)
+}
+
+/// The contours of the regions of `color`, in raster order of their starts. Regions
+/// touching only at a corner get contours of their own.
+fn trace(image: &GrayImage, color: u8) -> Vec<Contour> {
+    let inside = |x: i32, y: i32| {
+        x >= 0
+            && y >= 0
+            && (x as u32) < image.width()
+            && (y as u32) < image.height()
+            && image.get_pixel(x as u32, y as u32).0[0] == color
+    };
+    let mut traced = vec![false; (RESCALE_WIDTH * RESCALE_HEIGHT) as usize];
+    let mut contours = vec![];
+    for y in 0..RESCALE_HEIGHT as i32 {
+        for x in 0..RESCALE_WIDTH as i32 {
+            let i = (y * RESCALE_WIDTH as i32 + x) as usize;
+            if traced[i] || !inside(x, y) || inside(x, y - 1) {
+                continue;
+            }
+            let (mut cx, mut cy) = (x, y);
+            let mut direction = 0;
+            let mut turns = vec![];
+            loop {
+                if direction == 0 {
+                    // Going right along the top of the pixel below.
+                    traced[(cy * RESCALE_WIDTH as i32 + cx) as usize] = true;
+                }
+                cx += DIRECTIONS[direction].0;
+                cy += DIRECTIONS[direction].1;
+                // The pixels ahead of the corner, on the right and left of the path.
+                let (dx, dy) = DIRECTIONS[direction];
+                let (rx, ry) = DIRECTIONS[(direction + 1) % 4];
+                let pixel = |side: i32| {
+                    let px = 2 * cx + dx + side * rx;
+                    let py = 2 * cy + dy + side * ry;
+                    inside((px - 1).div_euclid(2), (py - 1).div_euclid(2))
+                };
+                let turn = match (pixel(1), pixel(-1)) {
+                    (false, _) => RIGHT,
+                    (true, false) => STRAIGHT,
+                    (true, true) => LEFT,
+                };
+                direction = match turn {
+                    RIGHT => (direction + 1) % 4,
+                    LEFT => (direction + 3) % 4,
+                    _ => direction,
+                };
+                turns.push(turn);
+                if (cx, cy) == (x, y) && direction == 0 {
+                    break;
+                }
+            }
+            contours.push(Contour {
+                start: (x as u32, y as u32),
+                turns,
+            });
+        }
+    }
+    contours
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitstream::{huffman_index, BitStream};
+    use crate::references::ReferenceCommand;
+
+    /// Reads a frame the way `decode_outlines` in the cart does.
+    fn decode(stream: &mut BitStream, code: &HuffmanCode<u8>) -> GrayImage {
+        let background = stream.read_bits(crate::BPP as u8).unwrap() as u8;
+        let mut screen =
+            GrayImage::from_pixel(RESCALE_WIDTH, RESCALE_HEIGHT, image::Luma([background]));
+        for color in (0..PALETTE.len() as u8).filter(|&color| color != background) {
+            let mut edges = vec![false; (RESCALE_WIDTH * RESCALE_HEIGHT) as usize];
+            let mut toggle = |x: u32, y: u32| {
+                if x < RESCALE_WIDTH {
+                    edges[(y * RESCALE_WIDTH + x) as usize] ^= true;
+                }
+            };
+            let mut i = -1;
+            for _ in 0..stream.read_int().unwrap() - 1 {
+                i += stream.read_int().unwrap() as i32;
+                let start = (i as u32 % RESCALE_WIDTH, i as u32 / RESCALE_WIDTH);
+                let (mut x, mut y, mut direction) = (start.0, start.1, 0);
+                loop {
+                    match direction {
+                        0 => x += 1,
+                        1 => {
+                            toggle(x, y);
+                            y += 1;
+                        }
+                        2 => x -= 1,
+                        _ => {
+                            y -= 1;
+                            toggle(x, y);
+                        }
+                    }
+                    let turn = code.values()
+                        [huffman_index(|| stream.read_one().unwrap(), code.length_counts())];
+                    direction = match turn {
+                        STRAIGHT => direction,
+                        LEFT => (direction + 3) % 4,
+                        _ => (direction + 1) % 4,
+                    };
+                    if (x, y) == start && direction == 0 {
+                        break;
+                    }
+                }
+            }
+            for y in 0..RESCALE_HEIGHT {
+                let mut inside = false;
+                for x in 0..RESCALE_WIDTH {
+                    inside ^= edges[(y * RESCALE_WIDTH + x) as usize];
+                    if inside {
+                        screen.put_pixel(x, y, image::Luma([color]));
+                    }
+                }
+            }
+        }
+        screen
+    }
+
+    /// Frames with regions that have holes, touch at corners and touch the edges.
+    fn frames() -> Vec<GrayImage> {
+        let colors = PALETTE.len() as u32;
+        let frame = |f: fn(i32, i32) -> u32| {
+            GrayImage::from_fn(RESCALE_WIDTH, RESCALE_HEIGHT, |x, y| {
+                image::Luma([(f(x as i32, y as i32) % colors) as u8])
+            })
+        };
+        vec![
+            frame(|_, _| 0),
+            frame(|_, _| 1),
+            frame(|x, y| ((x - 20).pow(2) + (y - 15).pow(2) < 120) as u32),
+            frame(|x, y| ((x - 20).pow(2) + (y - 15).pow(2)) as u32 / 40),
+            frame(|x, y| (x + y) as u32),
+            frame(|x, y| (x / 3 + y / 2) as u32),
+            frame(|x, y| (x * y % 7 == 0) as u32 + (x * 3 % 5 == 1) as u32),
+            frame(|x, y| ((x + 7 * y) as u32).wrapping_mul(2654435761) >> 29),
+        ]
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let frames = frames();
+        let data: Vec<_> = frames
+            .iter()
+            .map(|image| EncodedFrame {
+                duration: 1,
+                reference: ReferenceCommand::default(),
+                coding: encode_outlines(image),
+            })
+            .collect();
+        let code = turn_code(&data);
+        let mut bits = BitVec::new();
+        for encoded in &data {
+            let FrameCoding::Outlines(outlines) = &encoded.coding else {
+                unreachable!()
+            };
+            outlines.encode(&mut bits, |to, turn| code.encode_value(to, &turn));
+        }
+        let mut bytes = vec![];
+        bits.dump(&mut bytes).unwrap();
+
+        let mut stream = BitStream::new(&bytes);
+        for (i, image) in frames.iter().enumerate() {
+            assert!(&decode(&mut stream, &code) == image, "frame {i}");
+        }
+    }
+}
@@ -0,0 +1,11 @@
+//! Playback clock settings, shared with `tools/render-audio` so that it renders the
+//! music with the cart's timing.
+
+/// Rate of the cart's playback clock. WASM-4 calls update() 60 times a second.
+pub const TICK_RATE: u32 = 60;
+/// Whether the music jumps back to its loop point instead of ending. When set, the
+/// music also plays on when the video ends and starts over, rather than starting
+/// over with it; restarting with button 1 still restarts both.
+pub const LOOP_MUSIC: bool = false;
+/// Delay of the music relative to the video, in ticks. May be negative.
+pub const AUDIO_OFFSET: i32 = 0;
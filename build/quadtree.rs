@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+use image::GrayImage;
+
+use crate::huffman::HuffmanCode;
+use crate::{
+    scanline, value_sets, EncodedFrame, FrameCoding, Rect, MAX_CODE_LENGTH, PALETTE,
+    RESCALE_HEIGHT, RESCALE_WIDTH,
+};
+
+/// Node symbol for a node split into quadrants. The other symbols are run kinds: a
+/// color the whole node is filled with, or unchanged.
+pub const SPLIT: u8 = PALETTE.len() as u8 + 1;
+
+/// Codes the whole frame as a quadtree, with the node symbols in preorder.
+pub fn encode_quadtree(curr: &GrayImage, prev: &GrayImage) -> FrameCoding {
+    let mut nodes = vec![];
+    let frame = Rect {
+        x: 0,
+        y: 0,
+        w: RESCALE_WIDTH,
+        h: RESCALE_HEIGHT,
+    };
+    encode_node(curr, prev, frame, &mut nodes);
+    FrameCoding::Quadtree(nodes)
+}
+
+/// Huffman code for the nodes of the quadtree frames in `data`.
+pub fn node_code(data: &[EncodedFrame]) -> HuffmanCode<u8> {
+    let mut freq = HashMap::new();
+    for encoded in data {
+        if let FrameCoding::Quadtree(nodes) = &encoded.coding {
+            for &node in nodes {
+                *freq.entry(node).or_default() += 1;
+            }
+        }
+    }
+    HuffmanCode::new(freq, MAX_CODE_LENGTH)
+}
+
+fn encode_node(curr: &GrayImage, prev: &GrayImage, rect: Rect, nodes: &mut Vec<u8>) {
+    let set = value_sets(curr, prev, false, scanline(rect)).fold(u8::MAX, |a, b| a & b);
+    if set != 0 {
+        nodes.push(set.trailing_zeros() as u8);
+        return;
+    }
+    nodes.push(SPLIT);
+    for quadrant in quadrants(rect) {
+        encode_node(curr, prev, quadrant, nodes);
+    }
+}
+
+/// The non-empty quadrants of `rect`, the first row and column taking the extra
+/// pixel of odd sizes, mirroring `decode_quadtree` in the cart.
+fn quadrants(rect: Rect) -> impl Iterator<Item = Rect> {
+    let w = rect.w.div_ceil(2);
+    let h = rect.h.div_ceil(2);
+    [
+        (rect.x, rect.y, w, h),
+        (rect.x + w, rect.y, rect.w - w, h),
+        (rect.x, rect.y + h, w, rect.h - h),
+        (rect.x + w, rect.y + h, rect.w - w, rect.h - h),
+    ]
+    .into_iter()
+    .filter(|&(_, _, w, h)| w > 0 && h > 0)
+    .map(|(x, y, w, h)| Rect { x, y, w, h })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitstream::{huffman_index, BitStream};
+    use crate::bitvec::BitVec;
+    use crate::references::ReferenceCommand;
+
+    /// Draws the node at the front of `stream` and its children over `rect`, the way
+    /// `decode_quadtree` in the cart does.
+    fn decode_node(
+        stream: &mut BitStream,
+        code: &HuffmanCode<u8>,
+        screen: &mut GrayImage,
+        rect: Rect,
+    ) {
+        let node =
+            code.values()[huffman_index(|| stream.read_one().unwrap(), code.length_counts())];
+        if node == SPLIT {
+            for quadrant in quadrants(rect) {
+                decode_node(stream, code, screen, quadrant);
+            }
+        } else if node as usize != PALETTE.len() {
+            for (x, y) in scanline(rect) {
+                screen.put_pixel(x, y, image::Luma([node]));
+            }
+        }
+    }
+
+    /// A frame with a white rect of `w` by `h` pixels at `x`, `y`.
+    fn frame(x: u32, y: u32, w: u32, h: u32) -> GrayImage {
+        GrayImage::from_fn(RESCALE_WIDTH, RESCALE_HEIGHT, |px, py| {
+            image::Luma([((x..x + w).contains(&px) && (y..y + h).contains(&py)) as u8])
+        })
+    }
+
+    #[test]
+    fn quadrants_cover_the_rect() {
+        for (w, h) in [(1, 1), (1, 5), (7, 1), (7, 5), (40, 30)] {
+            let rect = Rect { x: 3, y: 2, w, h };
+            let mut covered: Vec<_> = quadrants(rect).flat_map(scanline).collect();
+            covered.sort_unstable();
+            let mut pixels: Vec<_> = scanline(rect).collect();
+            pixels.sort_unstable();
+            assert_eq!(covered, pixels);
+        }
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let frames = [
+            frame(0, 0, 0, 0),
+            frame(3, 5, 17, 11),
+            frame(3, 5, 17, 11),
+            frame(0, 0, RESCALE_WIDTH, RESCALE_HEIGHT),
+            frame(21, 1, 1, 29),
+            frame(7, 8, 9, 10),
+        ];
+        let data: Vec<_> = frames
+            .windows(2)
+            .map(|pair| EncodedFrame {
+                duration: 1,
+                reference: ReferenceCommand::default(),
+                coding: encode_quadtree(&pair[1], &pair[0]),
+            })
+            .collect();
+        let code = node_code(&data);
+        let mut bits = BitVec::new();
+        for encoded in &data {
+            let FrameCoding::Quadtree(nodes) = &encoded.coding else {
+                unreachable!()
+            };
+            for node in nodes {
+                code.encode_value(&mut bits, node);
+            }
+        }
+        let mut bytes = vec![];
+        bits.dump(&mut bytes).unwrap();
+
+        let mut stream = BitStream::new(&bytes);
+        let mut screen = frames[0].clone();
+        let whole = Rect {
+            x: 0,
+            y: 0,
+            w: RESCALE_WIDTH,
+            h: RESCALE_HEIGHT,
+        };
+        for curr in &frames[1..] {
+            decode_node(&mut stream, &code, &mut screen, whole);
+            assert!(&screen == curr);
+        }
+    }
+}
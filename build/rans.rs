@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+
+use crate::bitvec::{RansEncoder, RANS_SCALE_BITS};
+use crate::{EncodedFrame, Run, PALETTE};
+
+/// Static rANS table for runs: every run that occurs, with its quantized frequency.
+pub struct RansCode {
+    pub runs: Vec<Run>,
+    /// Cumulative frequencies of `runs`, followed by their total.
+    pub cumulative: Vec<u16>,
+    index: HashMap<Run, usize>,
+}
+
+impl RansCode {
+    /// The runs, packed the same way as in `runs-data.bin`.
+    pub fn values(&self) -> Vec<u32> {
+        self.runs
+            .iter()
+            .map(|run| run.kind as u32 + run.length * (PALETTE.len() as u32 + 1))
+            .collect()
+    }
+
+    /// Smallest integer type that holds every value.
+    pub fn value_type(&self) -> &'static str {
+        match self.values().into_iter().max().unwrap_or(0) {
+            0..=0xFFFF => "u16",
+            _ => "u32",
+        }
+    }
+
+    /// Size of the run values and cumulative frequencies in the cart.
+    pub fn table_bytes(&self) -> usize {
+        let value_bytes = match self.value_type() {
+            "u16" => 2,
+            _ => 4,
+        };
+        self.runs.len() * value_bytes + self.cumulative.len() * 2
+    }
+
+    pub fn new(run_freq: &HashMap<Run, u64>) -> Self {
+        let total_slots = 1u64 << RANS_SCALE_BITS;
+        assert!(
+            run_freq.len() as u64 <= total_slots,
+            "too many distinct runs for the rANS table"
+        );
+
+        let mut runs: Vec<_> = run_freq.iter().map(|(&run, &count)| (run, count)).collect();
+        runs.sort_by_key(|&(run, count)| (std::cmp::Reverse(count), run.kind, run.length));
+        let total: u64 = runs.iter().map(|&(_, count)| count).sum();
+        let mut freqs: Vec<_> = runs
+            .iter()
+            .map(|&(_, count)| (count * total_slots / total).max(1))
+            .collect();
+
+        // Rounding leaves the total a little off, so take the difference from the most
+        // common runs, where it costs the least.
+        let mut sum: u64 = freqs.iter().sum();
+        let mut i = 0;
+        while !freqs.is_empty() && sum != total_slots {
+            if sum < total_slots {
+                freqs[0] += total_slots - sum;
+                sum = total_slots;
+            } else {
+                let taken = (sum - total_slots).min(freqs[i] - 1);
+                freqs[i] -= taken;
+                sum -= taken;
+                i += 1;
+            }
+        }
+
+        let mut cumulative = vec![0];
+        let mut start = 0;
+        for freq in freqs {
+            start += freq;
+            cumulative.push(start as u16);
+        }
+        let runs: Vec<_> = runs.into_iter().map(|(run, _)| run).collect();
+        let index = runs.iter().enumerate().map(|(i, &run)| (run, i)).collect();
+        RansCode {
+            runs,
+            cumulative,
+            index,
+        }
+    }
+
+    /// Codes the runs of every rect, in the order the cart reads them.
+    pub fn encode_runs(&self, data: &[EncodedFrame]) -> Vec<u8> {
+        let mut encoder = RansEncoder::new();
+        let runs = data
+            .iter()
+            .flat_map(EncodedFrame::rects)
+            .flat_map(|rect| &rect.runs);
+        for run in runs.rev() {
+            let i = self.index[run];
+            let start = self.cumulative[i] as u32;
+            encoder.write(start, self.cumulative[i + 1] as u32 - start);
+        }
+        encoder.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitstream::RansDecoder;
+    use crate::references::ReferenceCommand;
+    use crate::{EncodedRect, FrameCoding, Rect};
+
+    /// Codes `runs` in one rect with a table built from their counts, and decodes them
+    /// with the cart's decoder.
+    fn round_trip(runs: &[Run]) {
+        let mut run_freq = HashMap::new();
+        for &run in runs {
+            *run_freq.entry(run).or_default() += 1;
+        }
+        let code = RansCode::new(&run_freq);
+        assert_eq!(code.cumulative.last(), Some(&(1 << RANS_SCALE_BITS)));
+        assert!(code.cumulative.windows(2).all(|w| w[0] < w[1]));
+
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            w: 1,
+            h: 1,
+        };
+        let data = [EncodedFrame {
+            duration: 1,
+            reference: ReferenceCommand::default(),
+            coding: FrameCoding::Rects(vec![EncodedRect {
+                rect,
+                order: 0,
+                motion: None,
+                xor: false,
+                runs: runs.to_vec(),
+            }]),
+        }];
+        let bytes = code.encode_runs(&data);
+        let mut decoder = RansDecoder::new(&bytes);
+        for run in runs {
+            assert_eq!(code.runs[decoder.read(&code.cumulative)], *run);
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let runs: Vec<_> = (0..3000u32)
+            .map(|i| Run {
+                length: 1 + (i * i) % 23 / 4,
+                kind: (i % 3) as u8,
+            })
+            .collect();
+        round_trip(&runs);
+    }
+
+    #[test]
+    fn rare_runs_keep_a_slot() {
+        // Hundreds of runs seen once beside one seen far more often than there are
+        // slots, so that rounding gives out more slots than there are.
+        let mut runs = vec![Run { length: 1, kind: 0 }; 100_000];
+        runs.extend((2..600).map(|length| Run { length, kind: 1 }));
+        round_trip(&runs);
+    }
+}
@@ -0,0 +1,164 @@
+use image::GrayImage;
+
+use crate::{RESCALE_HEIGHT, RESCALE_WIDTH};
+
+/// Most frames the cart keeps to code later frames against.
+const REFERENCE_SLOTS: usize = 16;
+
+/// What a frame does with the cart's cache of reference frames: restore one to the
+/// screen before it is decoded, and store itself once it is.
+#[derive(Clone, Copy, Default)]
+pub struct ReferenceCommand {
+    pub restore: Option<usize>,
+    pub store: Option<usize>,
+}
+
+impl ReferenceCommand {
+    pub fn is_empty(self) -> bool {
+        self.restore.is_none() && self.store.is_none()
+    }
+
+    /// The command's symbol, for a cache of `slots` slots.
+    pub fn symbol(self, slots: usize) -> usize {
+        let slot = |slot: Option<usize>| slot.map_or(0, |slot| slot + 1);
+        slot(self.restore) * (slots + 1) + slot(self.store)
+    }
+
+    /// The slots to restore and store, plus one or 0 for none, that `symbol` stands
+    /// for. `decode_reference` in the cart returns these.
+    pub fn slots(symbol: usize, slots: usize) -> (usize, usize) {
+        (symbol / (slots + 1), symbol % (slots + 1))
+    }
+}
+
+/// Which frames are kept as references and which could be coded against one,
+/// decided up front from the source frames.
+///
+/// Scene cuts are stored, replacing the least recently used reference when the
+/// cache is full. A frame is worth coding against a reference when it differs
+/// from it in less than half as many pixels as from the previous frame, as when
+/// a shot is repeated. Stored frames that nothing is coded against are dropped.
+pub struct ReferencePlan {
+    /// Number of slots used, the size of the cart's cache.
+    pub slots: usize,
+    /// For each frame, the slot it is stored in.
+    pub stores: Vec<Option<usize>>,
+    /// For each frame, the slot of the reference it could be coded against, and the
+    /// image stored there.
+    pub restores: Vec<Option<(usize, usize)>>,
+}
+
+impl ReferencePlan {
+    pub fn new(images: &[GrayImage]) -> Self {
+        // The image in each slot and the last frame that used it.
+        let mut slots: Vec<(usize, usize)> = vec![];
+        let mut stores = vec![];
+        let mut restores = vec![];
+        let mut restored = vec![false; images.len()];
+        for (frame, pair) in images.windows(2).enumerate() {
+            let changed = differences(&pair[1], &pair[0]);
+            let restore = slots
+                .iter()
+                .enumerate()
+                .map(|(slot, &(image, _))| (slot, image, differences(&pair[1], &images[image])))
+                .min_by_key(|&(_, _, differences)| differences)
+                .filter(|&(_, _, differences)| differences * 2 < changed)
+                .map(|(slot, image, _)| (slot, image));
+            if let Some((slot, image)) = restore {
+                slots[slot].1 = frame;
+                restored[image] = true;
+            }
+            restores.push(restore);
+
+            let scene_cut = changed * 4 > RESCALE_WIDTH * RESCALE_HEIGHT;
+            let store = match scene_cut && restore.is_none() {
+                false => None,
+                true if slots.len() < REFERENCE_SLOTS => {
+                    slots.push((frame + 1, frame));
+                    Some(slots.len() - 1)
+                }
+                true => {
+                    let (slot, _) = slots
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|&(_, &(_, last_used))| last_used)
+                        .unwrap();
+                    slots[slot] = (frame + 1, frame);
+                    Some(slot)
+                }
+            };
+            stores.push(store);
+        }
+        for (frame, store) in stores.iter_mut().enumerate() {
+            if !restored[frame + 1] {
+                *store = None;
+            }
+        }
+        ReferencePlan {
+            slots: stores.iter().flatten().max().map_or(0, |slot| slot + 1),
+            stores,
+            restores,
+        }
+    }
+}
+
+pub fn differences(a: &GrayImage, b: &GrayImage) -> u32 {
+    a.pixels().zip(b.pixels()).filter(|(a, b)| a != b).count() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame of scene `scene`, with `moved` pixels of it flipped.
+    fn shot(scene: u32, moved: u32) -> GrayImage {
+        GrayImage::from_fn(RESCALE_WIDTH, RESCALE_HEIGHT, |x, y| {
+            let i = y * RESCALE_WIDTH + x;
+            let hash = (i + scene * 7919).wrapping_mul(2654435761) >> 16;
+            image::Luma([(hash % 2 == 1) as u8 ^ (i < moved) as u8])
+        })
+    }
+
+    #[test]
+    fn slots_undo_symbol() {
+        for slots in [0, 1, 5] {
+            for restore in (0..slots).map(Some).chain([None]) {
+                for store in (0..slots).map(Some).chain([None]) {
+                    let symbol = ReferenceCommand { restore, store }.symbol(slots);
+                    let slot = |slot: Option<usize>| slot.map_or(0, |slot| slot + 1);
+                    assert_eq!(
+                        ReferenceCommand::slots(symbol, slots),
+                        (slot(restore), slot(store))
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn restores_what_the_cart_stored() {
+        // More scenes than slots, each coming back a few times in a different order.
+        let scenes = REFERENCE_SLOTS as u32 + 4;
+        let images: Vec<_> = (0..3)
+            .flat_map(|round| (0..scenes).map(move |scene| (scene * (round + 1)) % scenes))
+            .flat_map(|scene| [shot(scene, 0), shot(scene, 3)])
+            .collect();
+        let plan = ReferencePlan::new(&images);
+        assert!(plan.slots <= REFERENCE_SLOTS);
+        assert!(plan.restores.iter().any(Option::is_some));
+
+        // The image each of the cart's slots holds, following only the stores, as
+        // the frames coded without their restore still store.
+        let mut cache = vec![None; plan.slots];
+        for frame in 0..images.len() - 1 {
+            if let Some((slot, image)) = plan.restores[frame] {
+                assert_eq!(cache[slot], Some(image), "frame {}", frame + 1);
+                let restored = differences(&images[frame + 1], &images[image]);
+                assert!(restored * 2 < differences(&images[frame + 1], &images[frame]));
+            }
+            if let Some(slot) = plan.stores[frame] {
+                cache[slot] = Some(frame + 1);
+            }
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Result, Write};
+use std::path::Path;
+
+use crate::buckets::SegmentedRunCodes;
+use crate::{EncodedFrame, FrameBits, FrameCoding, Movie, Run, ORDERS};
+
+const GRAPH_WIDTH: usize = 1000;
+const GRAPH_HEIGHT: usize = 200;
+
+/// Writes `stats.json`, a per-frame `frames.csv` and a graph of frame sizes,
+/// `frames.svg`, into `dir`.
+pub fn write_report(data: &[EncodedFrame], movie: &Movie, dir: impl AsRef<Path>) -> Result<()> {
+    let dir = dir.as_ref();
+    let frame_bits: Vec<_> = data
+        .iter()
+        .enumerate()
+        .map(|(frame, encoded)| movie.frame_bits(frame, encoded))
+        .collect();
+
+    let mut totals = FrameBits::default();
+    let mut num_rects = BTreeMap::<usize, u64>::new();
+    let mut orderings = [0u64; ORDERS];
+    let mut motion_rects = 0u64;
+    let mut xor_rects = 0u64;
+    let mut quadtree_frames = 0u64;
+    let mut outline_frames = 0u64;
+    let mut restored_frames = 0u64;
+    let mut stored_frames = 0u64;
+    let mut runs = HashMap::<Run, u64>::new();
+    for (encoded, bits) in data.iter().zip(&frame_bits) {
+        totals.num_rects += bits.num_rects;
+        totals.positions += bits.positions;
+        totals.orders += bits.orders;
+        totals.runs += bits.runs;
+        totals.quadtree += bits.quadtree;
+        totals.outlines += bits.outlines;
+        totals.reference += bits.reference;
+        totals.duration += bits.duration;
+        match &encoded.coding {
+            FrameCoding::Rects(rects) => *num_rects.entry(rects.len()).or_default() += 1,
+            FrameCoding::Quadtree(_) => quadtree_frames += 1,
+            FrameCoding::Outlines(_) => outline_frames += 1,
+        }
+        if encoded.reference.restore.is_some() {
+            restored_frames += 1;
+        }
+        if encoded.reference.store.is_some() {
+            stored_frames += 1;
+        }
+        for rect in encoded.rects() {
+            if rect.has_header() {
+                orderings[rect.order] += 1;
+            }
+            if rect.motion.is_some() {
+                motion_rects += 1;
+            }
+            if rect.xor {
+                xor_rects += 1;
+            }
+            for run in &rect.runs {
+                *runs.entry(*run).or_default() += 1;
+            }
+        }
+    }
+    let mut runs: Vec<_> = runs.into_iter().collect();
+    runs.sort_by_key(|&(run, count)| (std::cmp::Reverse(count), run.kind, run.length));
+
+    let mut json = BufWriter::new(File::create(dir.join("stats.json"))?);
+    writeln!(json, "{{")?;
+    writeln!(json, "  \"frames\": {},", data.len())?;
+    writeln!(
+        json,
+        "  \"source_frames\": {},",
+        data.iter().map(|encoded| encoded.duration).sum::<u32>()
+    )?;
+    writeln!(json, "  \"run_coder\": \"{:?}\",", movie.run_coder)?;
+    writeln!(json, "  \"bytes\": {{")?;
+    writeln!(json, "    \"movie\": {},", movie.movie.bytes())?;
+    writeln!(
+        json,
+        "    \"run_length_counts\": {},",
+        movie.run_length_counts.len() * 2
+    )?;
+    writeln!(json, "    \"runs_data\": {},", movie.runs_data.bytes())?;
+    writeln!(json, "    \"runs\": {},", movie.runs.len())?;
+    writeln!(
+        json,
+        "    \"split_run_tables\": {},",
+        movie
+            .split_runs
+            .as_ref()
+            .map_or(0, SegmentedRunCodes::table_bytes)
+    )?;
+    writeln!(json, "    \"total\": {}", movie.size())?;
+    writeln!(json, "  }},")?;
+    writeln!(json, "  \"bits\": {{")?;
+    writeln!(json, "    \"num_rects\": {},", totals.num_rects)?;
+    writeln!(json, "    \"rect_positions\": {},", totals.positions)?;
+    writeln!(json, "    \"orders\": {},", totals.orders)?;
+    writeln!(json, "    \"runs\": {},", totals.runs)?;
+    writeln!(json, "    \"quadtree\": {},", totals.quadtree)?;
+    writeln!(json, "    \"outlines\": {},", totals.outlines)?;
+    writeln!(json, "    \"references\": {},", totals.reference)?;
+    writeln!(json, "    \"durations\": {},", totals.duration)?;
+    writeln!(
+        json,
+        "    \"per_frame\": {:.2}",
+        totals.total() as f64 / data.len().max(1) as f64
+    )?;
+    writeln!(json, "  }},")?;
+    let histogram: Vec<_> = num_rects
+        .iter()
+        .map(|(count, frames)| format!("\"{count}\": {frames}"))
+        .collect();
+    writeln!(json, "  \"rect_counts\": {{{}}},", histogram.join(", "))?;
+    let orderings: Vec<_> = orderings.iter().map(u64::to_string).collect();
+    writeln!(json, "  \"orderings\": [{}],", orderings.join(", "))?;
+    writeln!(json, "  \"motion_rects\": {motion_rects},")?;
+    writeln!(json, "  \"xor_rects\": {xor_rects},")?;
+    writeln!(json, "  \"quadtree_frames\": {quadtree_frames},")?;
+    writeln!(json, "  \"outline_frames\": {outline_frames},")?;
+    writeln!(json, "  \"restored_frames\": {restored_frames},")?;
+    writeln!(json, "  \"stored_frames\": {stored_frames},")?;
+    writeln!(json, "  \"runs\": [")?;
+    for (i, &(run, count)) in runs.iter().enumerate() {
+        writeln!(
+            json,
+            "    {{\"kind\": {}, \"length\": {}, \"count\": {count}, \"code_length\": {}}}{}",
+            run.kind,
+            run.length,
+            movie.run_code_length(None, &run).unwrap(),
+            if i + 1 == runs.len() { "" } else { "," }
+        )?;
+    }
+    writeln!(json, "  ]")?;
+    writeln!(json, "}}")?;
+    json.flush()?;
+
+    let mut csv = BufWriter::new(File::create(dir.join("frames.csv"))?);
+    writeln!(
+        csv,
+        "frame,rects,num_rects_bits,position_bits,order_bits,run_bits,quadtree_bits,outline_bits,reference_bits,duration,duration_bits,total_bits"
+    )?;
+    for (i, (encoded, bits)) in data.iter().zip(&frame_bits).enumerate() {
+        writeln!(
+            csv,
+            "{},{},{},{},{},{},{},{},{},{},{},{}",
+            i + 1,
+            encoded.rects().len(),
+            bits.num_rects,
+            bits.positions,
+            bits.orders,
+            bits.runs,
+            bits.quadtree,
+            bits.outlines,
+            bits.reference,
+            encoded.duration,
+            bits.duration,
+            bits.total()
+        )?;
+    }
+    csv.flush()?;
+
+    let mut svg = BufWriter::new(File::create(dir.join("frames.svg"))?);
+    let max_bits = frame_bits
+        .iter()
+        .map(|b| b.total())
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    writeln!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{GRAPH_WIDTH}\" height=\"{GRAPH_HEIGHT}\">"
+    )?;
+    writeln!(svg, "<rect width=\"100%\" height=\"100%\" fill=\"white\"/>")?;
+    write!(svg, "<polyline fill=\"none\" stroke=\"black\" points=\"")?;
+    for (i, bits) in frame_bits.iter().enumerate() {
+        let x = i as f64 * GRAPH_WIDTH as f64 / data.len().max(1) as f64;
+        let y = GRAPH_HEIGHT as f64 * (1.0 - bits.total() as f64 / max_bits as f64);
+        write!(svg, "{x:.1},{y:.1} ")?;
+    }
+    writeln!(svg, "\"/>")?;
+    writeln!(
+        svg,
+        "<text x=\"4\" y=\"14\" font-size=\"12\">{max_bits} bits</text>"
+    )?;
+    writeln!(svg, "</svg>")?;
+    svg.flush()?;
+
+    println!("cargo:warning=Statistics written to {}", dir.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode_movie;
+    use std::fs;
+
+    #[test]
+    fn empty_movie_has_numbers() {
+        let dir = std::env::temp_dir().join(format!("stats-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        write_report(&[], &encode_movie(&[]), &dir).unwrap();
+        let json = fs::read_to_string(dir.join("stats.json")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(json.contains("\"per_frame\": 0.00"), "{json}");
+        assert!(!json.contains("NaN"), "{json}");
+    }
+}
@@ -2,10 +2,7 @@
 
 use crate::bitstream::BitStream;
 
-const PULSE_1: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/pulse_one.bin"));
-const PULSE_2: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/pulse_two.bin"));
-const TRIANGLE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/triangle.bin"));
-const NOISE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/noise.bin"));
+include!(concat!(env!("OUT_DIR"), "/music.rs"));
 
 pub struct Program {
     pulse_one: ChannelPlayer,
@@ -17,58 +14,10 @@ pub struct Program {
 impl Program {
     pub fn new() -> Self {
         Self {
-            pulse_one: ChannelPlayer::new(
-                ChannelReader {
-                    stream: BitStream::new(PULSE_1),
-                    delta_bits: 3,
-                    deltas: &[13, 14, 26, 27, 890, 1676],
-                    length_bits: 2,
-                    lengths: &[9, 22, 35],
-                    pitch_bits: 5,
-                    pitches: &[277, 294, 311, 330, 349, 370, 392, 415, 440, 466, 494, 554, 587, 622, 659, 698, 740, 784],
-                },
-                Channel::PulseOne,
-                30,
-            ),
-            pulse_two: ChannelPlayer::new(
-                ChannelReader {
-                    stream: BitStream::new(PULSE_2),
-                    delta_bits: 3,
-                    deltas: &[0, 6, 7, 13, 14, 26, 27, 2541],
-                    length_bits: 0,
-                    lengths: &[2],
-                    pitch_bits: 0,
-                    pitches: &[165],
-                },
-                Channel::PulseTwo,
-                30,
-            ),
-            triangle: ChannelPlayer::new(
-                ChannelReader {
-                    stream: BitStream::new(TRIANGLE),
-                    delta_bits: 4,
-                    deltas: &[6, 7, 13, 14, 19, 20, 26, 104, 105, 158, 209, 210, 837],
-                    length_bits: 3,
-                    lengths: &[3, 9, 16, 22, 101, 206],
-                    pitch_bits: 5,
-                    pitches: &[31, 33, 35, 37, 39, 41, 46, 52, 55, 62, 65, 69, 73, 78, 92, 98, 104],
-                },
-                Channel::Triangle,
-                100,
-            ),
-            noise: ChannelPlayer::new(
-                ChannelReader {
-                    stream: BitStream::new(NOISE),
-                    delta_bits: 4,
-                    deltas: &[12, 26, 27, 40, 41, 52, 53, 786, 841, 863],
-                    length_bits: 1,
-                    lengths: &[3, 9],
-                    pitch_bits: 0,
-                    pitches: &[698],
-                },
-                Channel::Noise,
-                30,
-            )
+            pulse_one: ChannelPlayer::new(PULSE_ONE, Channel::PulseOne, 30),
+            pulse_two: ChannelPlayer::new(PULSE_TWO, Channel::PulseTwo, 30),
+            triangle: ChannelPlayer::new(TRIANGLE, Channel::Triangle, 100),
+            noise: ChannelPlayer::new(NOISE, Channel::Noise, 30),
         }
     }
 
@@ -78,6 +27,27 @@ impl Program {
         self.triangle.tick();
         self.noise.tick();
     }
+
+    /// Plays a one-shot sound effect, taking its channel away from the music until
+    /// the effect finishes. The music goes on silently meanwhile and picks up where it
+    /// is once the effect is over. An effect only cuts off a playing effect of the same
+    /// or lower priority; returns whether the effect was played.
+    pub fn play_effect(&mut self, t: Tone, priority: u8) -> bool {
+        let player = match t.channel {
+            Channel::PulseOne => &mut self.pulse_one,
+            Channel::PulseTwo => &mut self.pulse_two,
+            Channel::Triangle => &mut self.triangle,
+            Channel::Noise => &mut self.noise,
+        };
+        if player.effect_ticks != 0 && player.effect_priority > priority {
+            return false;
+        }
+        player.effect_ticks =
+            t.attack as u16 + t.decay as u16 + t.sustain as u16 + t.release as u16;
+        player.effect_priority = priority;
+        tone(t);
+        true
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -92,54 +62,78 @@ pub enum Channel {
 pub struct Tone {
     pub start_freq: u16,
     pub end_freq: u16,
-    pub attack: u16,
-    pub decay: u16,
-    pub sustain: u16,
-    pub release: u16,
+    pub attack: u8,
+    pub decay: u8,
+    pub sustain: u8,
+    pub release: u8,
     pub peak: u8,
     pub volume: u8,
     pub channel: Channel,
+    pub mode: u8,
 }
 
 fn tone(t: Tone) {
     let frequency = (t.end_freq as u32) << 16 | t.start_freq as u32;
-    let mut duration = t.attack as u32;
-    duration = (duration << 16) | t.decay as u32;
-    duration = (duration << 16) | t.sustain as u32;
-    duration = (duration << 16) | t.release as u32;
+    let duration = (t.attack as u32) << 24
+        | (t.decay as u32) << 16
+        | (t.release as u32) << 8
+        | t.sustain as u32;
     crate::wasm4::tone(
         frequency,
         duration,
         (t.peak as u32) << 8 | t.volume as u32,
-        t.channel as u32,
+        t.channel as u32 | t.mode as u32,
     );
 }
 
 struct Note {
     delta: u32,
-    length: u16,
+    length: u8,
     pitch: u16,
+    mode: u8,
 }
 
 struct ChannelReader {
+    data: &'static [u8],
     stream: BitStream<'static>,
+    index: u32,
+    notes: u32,
+    loop_note: u32,
+    loop_bit: u32,
+    loop_delta: u32,
     delta_bits: u8,
     deltas: &'static [u32],
     length_bits: u8,
-    lengths: &'static [u16],
+    lengths: &'static [u8],
     pitch_bits: u8,
     pitches: &'static [u16],
+    mode_bits: u8,
+    modes: &'static [u8],
 }
 
 impl ChannelReader {
     fn next(&mut self) -> Option<Note> {
+        let mut loop_delta = None;
+        if self.index == self.notes {
+            if !LOOPING || self.loop_note == self.notes {
+                return None;
+            }
+            self.index = self.loop_note;
+            self.stream = BitStream::new(&self.data[self.loop_bit as usize / 8..]);
+            self.stream.read_bits((self.loop_bit % 8) as u8);
+            loop_delta = Some(self.loop_delta);
+        }
+        self.index += 1;
+
         let delta = self.deltas[self.stream.read_bits(self.delta_bits)? as usize];
         let length = self.lengths[self.stream.read_bits(self.length_bits)? as usize];
         let pitch = self.pitches[self.stream.read_bits(self.pitch_bits)? as usize];
+        let mode = self.modes[self.stream.read_bits(self.mode_bits)? as usize];
         Some(Note {
-            delta,
+            delta: loop_delta.unwrap_or(delta),
             length,
             pitch,
+            mode,
         })
     }
 }
@@ -149,6 +143,13 @@ struct ChannelPlayer {
     channel: Channel,
     volume: u8,
     note: Option<Note>,
+    /// The music's last tone and how many more ticks it sounds for, to resume it with
+    /// when an effect ends.
+    sounding: Option<(Tone, u8)>,
+    effect_ticks: u16,
+    effect_priority: u8,
+    /// Whether an effect ended on the last tick.
+    resume: bool,
 }
 
 impl ChannelPlayer {
@@ -159,10 +160,18 @@ impl ChannelPlayer {
             channel,
             note,
             volume,
+            sounding: None,
+            effect_ticks: 0,
+            effect_priority: 0,
+            resume: false,
         }
     }
 
     fn tick(&mut self) {
+        if let Some((_, left)) = &mut self.sounding {
+            *left = left.saturating_sub(1);
+        }
+        let mut started = false;
         if let Some(note) = &mut self.note {
             if note.delta != 0 {
                 note.delta -= 1;
@@ -178,18 +187,33 @@ impl ChannelPlayer {
                     channel: self.channel,
                     peak: self.volume,
                     volume: self.volume,
+                    mode: note.mode,
                 };
                 if let Channel::Noise = t.channel {
-                    t.release = note.length;
-                    t.decay = 1;
-                    t.sustain = 0;
                     t.peak = 100;
                     t.volume = 5;
                     t.end_freq = 1000;
                 }
-                tone(t);
+                self.sounding = Some((t, note.length));
+                started = true;
                 self.note = self.reader.next();
             }
         }
+
+        if self.effect_ticks != 0 {
+            self.effect_ticks -= 1;
+            self.resume = self.effect_ticks == 0;
+            return;
+        }
+        match self.sounding {
+            Some((t, _)) if started => tone(t),
+            // Play what is left of the note the effect cut off or that came due under it
+            Some((mut t, left)) if self.resume && left > 0 => {
+                t.sustain = left;
+                tone(t);
+            }
+            _ => {}
+        }
+        self.resume = false;
     }
 }
@@ -5,7 +5,7 @@ pub struct BitStream<'a> {
 }
 
 impl BitStream<'_> {
-    pub const fn new(from: &[u8]) -> BitStream {
+    pub const fn new(from: &[u8]) -> BitStream<'_> {
         BitStream {
             from,
             current: 0,
@@ -56,4 +56,163 @@ impl BitStream<'_> {
     pub fn read_int(&mut self) -> Option<u32> {
         self.read_fibonacci()
     }
-}
\ No newline at end of file
+
+    pub fn read_signed(&mut self) -> Option<i32> {
+        let v = self.read_int()? - 1;
+        Some((v >> 1) as i32 ^ -((v & 1) as i32))
+    }
+}
+
+/// Reads the rest of a run length in `bucket`, DEFLATE style: the first four buckets
+/// are single lengths, and each later pair of buckets covers a power of two.
+pub fn bucket_length(stream: &mut BitStream, bucket: u32) -> u32 {
+    if bucket < 4 {
+        return bucket + 1;
+    }
+    let extra_bits = bucket / 2 - 1;
+    ((2 | bucket & 1) << extra_bits | stream.read_bits(extra_bits as u8).unwrap()) + 1
+}
+
+/// Decodes a canonical Huffman codeword into its index in codeword order, given
+/// the number of codewords of each length.
+pub fn huffman_index(mut next: impl FnMut() -> bool, length_counts: &[u16]) -> usize {
+    // A code with a single value or none has no codewords with bits.
+    if length_counts.iter().sum::<u16>() < 2 {
+        return 0;
+    }
+    // Each length's codewords follow on from the previous length's, so keep track
+    // of the first codeword and index of the current length.
+    let mut code = 0;
+    let mut first = 0;
+    let mut index = 0;
+    for &count in length_counts {
+        code |= next() as usize;
+        if code - first < count as usize {
+            return index + code - first;
+        }
+        index += count as usize;
+        first = (first + count as usize) << 1;
+        code <<= 1;
+    }
+    unreachable!()
+}
+
+/// Probabilities are fixed point with this many bits, and adapt by 1/32 of the
+/// distance to 0 or 1 after every bit.
+pub const PROBABILITY_BITS: u32 = 11;
+pub const PROBABILITY_HALF: u16 = 1 << (PROBABILITY_BITS - 1);
+const ADAPT_SHIFT: u32 = 5;
+
+/// Decoder for the adaptive binary range coder written by the build script's
+/// `RangeEncoder`.
+pub struct RangeDecoder<'a> {
+    from: &'a [u8],
+    range: u32,
+    code: u32,
+}
+
+impl RangeDecoder<'_> {
+    pub fn new(from: &[u8]) -> RangeDecoder<'_> {
+        let mut decoder = RangeDecoder {
+            from,
+            range: u32::MAX,
+            code: 0,
+        };
+        for _ in 0..4 {
+            decoder.code = decoder.code << 8 | decoder.next_byte();
+        }
+        decoder
+    }
+
+    fn next_byte(&mut self) -> u32 {
+        match self.from.split_first() {
+            Some((&next, rest)) => {
+                self.from = rest;
+                next as u32
+            }
+            None => 0,
+        }
+    }
+
+    fn normalize(&mut self) {
+        if self.range < 1 << 24 {
+            self.range <<= 8;
+            self.code = self.code << 8 | self.next_byte();
+        }
+    }
+
+    /// Reads a bit whose chance of being 0 is `probability`, then adapts it.
+    pub fn read_bit(&mut self, probability: &mut u16) -> bool {
+        let bound = (self.range >> PROBABILITY_BITS) * *probability as u32;
+        let bit = self.code >= bound;
+        if bit {
+            self.code -= bound;
+            self.range -= bound;
+            *probability -= *probability >> ADAPT_SHIFT;
+        } else {
+            self.range = bound;
+            *probability += ((1 << PROBABILITY_BITS) - *probability) >> ADAPT_SHIFT;
+        }
+        self.normalize();
+        bit
+    }
+
+    /// Reads `count` equally likely bits, most significant first.
+    pub fn read_direct(&mut self, count: u32) -> u32 {
+        let mut bits = 0;
+        for _ in 0..count {
+            self.range >>= 1;
+            let bit = self.code >= self.range;
+            if bit {
+                self.code -= self.range;
+            }
+            bits = bits << 1 | bit as u32;
+            self.normalize();
+        }
+        bits
+    }
+}
+
+/// rANS decoder with a static frequency table, reading the build script's `RansEncoder`.
+pub struct RansDecoder<'a> {
+    from: &'a [u8],
+    state: u32,
+}
+
+/// Frequencies add up to 2^RANS_SCALE_BITS.
+pub const RANS_SCALE_BITS: u32 = 12;
+const RANS_LOWER_BOUND: u32 = 1 << 23;
+
+impl RansDecoder<'_> {
+    pub fn new(from: &[u8]) -> RansDecoder<'_> {
+        let mut decoder = RansDecoder { from, state: 0 };
+        for i in 0..4 {
+            decoder.state |= decoder.next_byte() << (i * 8);
+        }
+        decoder
+    }
+
+    fn next_byte(&mut self) -> u32 {
+        match self.from.split_first() {
+            Some((&next, rest)) => {
+                self.from = rest;
+                next as u32
+            }
+            None => 0,
+        }
+    }
+
+    /// Reads the index of a symbol, given the cumulative frequencies of all symbols
+    /// followed by the total.
+    pub fn read(&mut self, cumulative: &[u16]) -> usize {
+        let slot = self.state & ((1 << RANS_SCALE_BITS) - 1);
+        let symbol = cumulative.partition_point(|&c| c as u32 <= slot) - 1;
+        let start = cumulative[symbol] as u32;
+        let freq = cumulative[symbol + 1] as u32 - start;
+        self.state = freq * (self.state >> RANS_SCALE_BITS) + slot - start;
+        while self.state < RANS_LOWER_BOUND {
+            self.state = self.state << 8 | self.next_byte();
+        }
+        symbol
+    }
+}
@@ -3,6 +3,7 @@
 
 mod audio;
 mod bitstream;
+mod order;
 mod wasm4;
 
 include!(concat!(env!("OUT_DIR"), "/generated.rs"));
@@ -10,27 +11,127 @@ use generated::*;
 
 use core::mem::MaybeUninit;
 
-use bitstream::BitStream;
+use bitstream::{
+    bucket_length, huffman_index, BitStream, RangeDecoder, RansDecoder, PROBABILITY_HALF,
+};
+use order::{get_xy, next_xy};
 
 const MOVIE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/movie.bin"));
-const RUNS_TREE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/runs-tree.bin"));
 const RUNS_DATA: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/runs-data.bin"));
+const RUNS: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/runs.bin"));
 
 const BPP: u8 = generated::PALETTE.len().trailing_zeros() as u8;
+const KINDS: usize = (1 << BPP) + 1;
+const KIND_BITS: u32 = usize::BITS - (KINDS - 1).leading_zeros();
+const LENGTH_BITS: usize = 16;
+/// Number of pixel traversal orders, see `get_xy`.
+const ORDERS: u32 = 10;
+/// Flag in a rect's header, after its order, for rects predicted by motion.
+const MOTION_FLAG: u32 = 1;
+/// Flag in a rect's header for rects whose runs are XORed onto the previous frame.
+const XOR_FLAG: u32 = 2;
+/// Number of rects that marks a frame coded as a quadtree.
+const QUADTREE_FRAME: u32 = u32::MAX;
+/// Number of rects that marks a frame starting with a reference command.
+const REFERENCE_FRAME: u32 = QUADTREE_FRAME - 1;
+/// Number of rects that marks a frame shown for the number of frames that follows.
+const DURATION_FRAME: u32 = QUADTREE_FRAME - 2;
+/// Number of rects that marks a frame drawn from outlines.
+const OUTLINE_FRAME: u32 = QUADTREE_FRAME - 3;
+/// Quadtree node split into quadrants. The other nodes are run kinds.
+const QUADTREE_SPLIT: u32 = KINDS as u32;
 
 const PIXEL_SIZE: u32 = match (160 / WIDTH, 160 / HEIGHT) {
     (w, h) if w < h => w,
     (_, h) => h,
 };
 
-static mut STATE: MaybeUninit<(BitStream, u32, u32, audio::Program)> = MaybeUninit::uninit();
+#[allow(dead_code)] // only the coder selected by RUN_CODER is constructed
+#[derive(PartialEq, Eq)]
+enum RunCoder {
+    Huffman,
+    Arithmetic,
+    Rans,
+}
+
+#[allow(dead_code)] // only the layout chosen by the build script is constructed
+#[derive(PartialEq, Eq)]
+enum RunLayout {
+    Joint,
+    Split,
+}
+
+/// Adaptive probabilities for range coded runs, mirroring `build/arithmetic.rs`.
+struct RunContexts {
+    kinds: [[u16; 1 << KIND_BITS]; KINDS + 1],
+    exponents: [[u16; LENGTH_BITS]; KINDS],
+    mantissas: [[u16; LENGTH_BITS]; KINDS],
+}
+
+struct Runs {
+    decoder: RangeDecoder<'static>,
+    contexts: RunContexts,
+    rans: RansDecoder<'static>,
+    /// Huffman tables used for the current segment.
+    table: usize,
+}
+
+impl Runs {
+    fn new() -> Self {
+        Runs {
+            decoder: RangeDecoder::new(RUNS),
+            rans: RansDecoder::new(RUNS),
+            table: 0,
+            contexts: RunContexts {
+                kinds: [[PROBABILITY_HALF; 1 << KIND_BITS]; KINDS + 1],
+                exponents: [[PROBABILITY_HALF; LENGTH_BITS]; KINDS],
+                mantissas: [[PROBABILITY_HALF; LENGTH_BITS]; KINDS],
+            },
+        }
+    }
+}
+
+const REFERENCE_BYTES: usize = (WIDTH * HEIGHT * BPP as u32).div_ceil(8) as usize;
+
+/// Frames kept to restore later, packed `BPP` bits per pixel.
+static mut REFERENCES: [[u8; REFERENCE_BYTES]; REFERENCE_SLOTS] =
+    [[0; REFERENCE_BYTES]; REFERENCE_SLOTS];
+
+/// Flags for the vertical edges of outlines, set at the pixel right of each edge.
+/// Empty when no frame is drawn from outlines.
+static mut OUTLINE_EDGES: [u8; OUTLINE_EDGE_BYTES] = [0; OUTLINE_EDGE_BYTES];
+
+static mut STATE: MaybeUninit<(BitStream, u32, u32, audio::Program, Runs, u32, u8)> =
+    MaybeUninit::uninit();
+
+/// Beep played when the video is restarted.
+const RESTART_BEEP: audio::Tone = audio::Tone {
+    start_freq: 880,
+    end_freq: 0,
+    attack: 0,
+    decay: 0,
+    sustain: 6,
+    release: 4,
+    peak: 40,
+    volume: 40,
+    channel: audio::Channel::PulseOne,
+    mode: 0,
+};
 
 #[no_mangle]
 fn start() {
     unsafe {
         *wasm4::SYSTEM_FLAGS =
             wasm4::SYSTEM_PRESERVE_FRAMEBUFFER | wasm4::SYSTEM_HIDE_GAMEPAD_OVERLAY;
-        STATE = MaybeUninit::new((BitStream::new(MOVIE), 0, 0, audio::Program::new()));
+        STATE = MaybeUninit::new((
+            BitStream::new(MOVIE),
+            0,
+            0,
+            audio::Program::new(),
+            Runs::new(),
+            0,
+            *wasm4::GAMEPAD1,
+        ));
         // Load palette
         let palette = &mut *wasm4::PALETTE;
         for i in 0..generated::PALETTE.len() {
@@ -48,85 +149,274 @@ fn start() {
 fn update() {
     let state = unsafe { STATE.assume_init_mut() };
 
-    state.1 += FRAMERATE;
+    // state.6 is the gamepad on the last update, so that a held button acts once
+    let gamepad = unsafe { *wasm4::GAMEPAD1 };
+    if gamepad & !state.6 & wasm4::BUTTON_1 != 0 {
+        start();
+        state.3.play_effect(RESTART_BEEP, 0);
+    }
+    state.6 = gamepad;
+
+    // Both the video and the music are driven off the playback clock in state.1
+    state.1 += 1;
 
-    while state.1 >= 60 {
-        state.1 -= 60;
+    // state.5 is when the next frame is due, in frames at FRAMERATE
+    while state.5 < state.1 * FRAMERATE / TICK_RATE {
         if state.2 == FRAMECOUNT {
-            start();
-            return;
+            if !LOOP_MUSIC {
+                start();
+                return;
+            }
+            // Start the video over, with the music playing on to its loop point
+            unsafe { (*wasm4::FRAMEBUFFER).fill(0) };
+            (state.0, state.1, state.2, state.4, state.5) =
+                (BitStream::new(MOVIE), 0, 0, Runs::new(), 0);
+            break;
         }
         state.2 += 1;
-        decode_frame(&mut state.0);
+        state.5 += decode_frame(&mut state.0, &mut state.4, state.2 - 1);
     }
 
     state.3.update();
 }
 
-fn decode_frame(stream: &mut BitStream) {
+/// Decodes the next frame onto the screen. Returns how long the frame is shown for.
+fn decode_frame(stream: &mut BitStream, runs: &mut Runs, frame: u32) -> u32 {
+    if let Some(&(_, table)) = RUN_SEGMENTS.iter().find(|&&(start, _)| start == frame) {
+        runs.table = table as usize;
+    }
     if BPP == 1 {
         undo_smooth_filter();
     }
 
-    let mut i = -1;
-    for _ in 0..decode_num_rects(|| stream.read_one().unwrap()) {
-        i += stream.read_int().unwrap() as i32;
-        let (x, y) = get_xy(i as u32, 0, WIDTH, HEIGHT);
-        let (tx, ty) = get_xy(i as u32 + stream.read_int().unwrap() - 1, 0, WIDTH, HEIGHT);
-        let w = tx - x + 1;
-        let h = ty - y + 1;
+    let mut num_rects = decode_num_rects(|| stream.read_one().unwrap());
+    let mut duration = 1;
+    if num_rects == DURATION_FRAME {
+        duration = stream.read_int().unwrap();
+        num_rects = decode_num_rects(|| stream.read_one().unwrap());
+    }
+    let mut store = 0;
+    if num_rects == REFERENCE_FRAME {
+        let restore;
+        (restore, store) = decode_reference(|| stream.read_one().unwrap());
+        if restore > 0 {
+            restore_reference(restore - 1);
+        }
+        num_rects = decode_num_rects(|| stream.read_one().unwrap());
+    }
+    if num_rects == QUADTREE_FRAME {
+        decode_quadtree(stream, 0, 0, WIDTH, HEIGHT);
+    } else if num_rects == OUTLINE_FRAME {
+        decode_outlines(stream);
+    } else {
+        let mut i = -1;
+        for _ in 0..num_rects {
+            i += stream.read_int().unwrap() as i32;
+            let (x, y) = get_xy(i as u32, 0, WIDTH, HEIGHT);
+            let (tx, ty) = get_xy(i as u32 + stream.read_int().unwrap() - 1, 0, WIDTH, HEIGHT);
+            let w = tx - x + 1;
+            let h = ty - y + 1;
 
-        decode_rect(stream, x, y, w, h);
+            decode_rect(stream, runs, x, y, w, h);
+        }
+    }
+    if store > 0 {
+        store_reference(store - 1);
     }
 
     if BPP == 1 {
         apply_smooth_filter();
     }
+    duration
+}
+
+fn restore_reference(slot: usize) {
+    let reference = unsafe { &REFERENCES[slot] };
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let i = ((y * WIDTH + x) * BPP as u32) as usize;
+            set(x, y, reference[i / 8] >> (i % 8) & ((1 << BPP) - 1));
+        }
+    }
 }
 
-fn decode_rect(stream: &mut BitStream, x: u32, y: u32, w: u32, h: u32) {
-    let order = match w == 1 || h == 1 {
+fn store_reference(slot: usize) {
+    let reference = unsafe { &mut REFERENCES[slot] };
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let i = ((y * WIDTH + x) * BPP as u32) as usize;
+            reference[i / 8] &= !(((1 << BPP) - 1) << (i % 8));
+            reference[i / 8] |= get(x, y) << (i % 8);
+        }
+    }
+}
+
+/// Fills the screen with a background color, then fills the outlines of each other
+/// color with it, a pixel being inside when an odd number of edges is left of it.
+fn decode_outlines(stream: &mut BitStream) {
+    let background = stream.read_bits(BPP).unwrap() as u8;
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            set(x, y, background);
+        }
+    }
+    let edges = unsafe { &mut *core::ptr::addr_of_mut!(OUTLINE_EDGES) };
+    for color in (0..1 << BPP).filter(|&color| color != background) {
+        edges.fill(0);
+        let mut i = -1;
+        for _ in 0..stream.read_int().unwrap() - 1 {
+            i += stream.read_int().unwrap() as i32;
+            let start = (i as u32 % WIDTH, i as u32 / WIDTH);
+            // Steps go right, down, left and up, turning clockwise as the direction grows.
+            let (mut x, mut y, mut direction) = (start.0, start.1, 0);
+            loop {
+                match direction {
+                    0 => x += 1,
+                    1 => {
+                        toggle_edge(edges, x, y);
+                        y += 1;
+                    }
+                    2 => x -= 1,
+                    _ => {
+                        y -= 1;
+                        toggle_edge(edges, x, y);
+                    }
+                }
+                direction = match decode_turn(|| stream.read_one().unwrap()) {
+                    0 => direction,
+                    1 => (direction + 3) % 4,
+                    _ => (direction + 1) % 4,
+                };
+                if (x, y) == start && direction == 0 {
+                    break;
+                }
+            }
+        }
+        for y in 0..HEIGHT {
+            let mut inside = false;
+            for x in 0..WIDTH {
+                let i = (y * WIDTH + x) as usize;
+                inside ^= edges[i / 8] >> (i % 8) & 1 != 0;
+                if inside {
+                    set(x, y, color);
+                }
+            }
+        }
+    }
+}
+
+fn toggle_edge(edges: &mut [u8], x: u32, y: u32) {
+    if x < WIDTH {
+        let i = (y * WIDTH + x) as usize;
+        edges[i / 8] ^= 1 << (i % 8);
+    }
+}
+
+fn decode_quadtree(stream: &mut BitStream, x: u32, y: u32, w: u32, h: u32) {
+    let node = decode_quadtree_node(|| stream.read_one().unwrap());
+    if node == QUADTREE_SPLIT {
+        let (w1, h1) = (w.div_ceil(2), h.div_ceil(2));
+        for (x, y, w, h) in [
+            (x, y, w1, h1),
+            (x + w1, y, w - w1, h1),
+            (x, y + h1, w1, h - h1),
+            (x + w1, y + h1, w - w1, h - h1),
+        ] {
+            if w > 0 && h > 0 {
+                decode_quadtree(stream, x, y, w, h);
+            }
+        }
+    } else if node != 1 << BPP {
+        for y in y..y + h {
+            for x in x..x + w {
+                set(x, y, node as u8);
+            }
+        }
+    }
+}
+
+fn decode_rect(stream: &mut BitStream, runs: &mut Runs, x: u32, y: u32, w: u32, h: u32) {
+    let header = match w == 1 || h == 1 {
         true => 0,
-        false => decode_order(|| stream.read_one().unwrap()),
+        false => decode_order(|| stream.read_one().unwrap()) as u32,
     };
+    let (order, flags) = (header % ORDERS, header / ORDERS);
+    if flags & MOTION_FLAG != 0 {
+        let dx = stream.read_signed().unwrap();
+        let dy = stream.read_signed().unwrap();
+        copy_shifted(x, y, w, h, dx, dy);
+    }
 
     let mut i = 0;
+    let mut p = 0;
+    let mut prev_kind = KINDS as u32;
     while i < w * h {
-        let index = huffman_index(stream, RUNS_TREE) * RUN_DATA_SIZE as usize;
-        let mut rundata = BitStream::new(&RUNS_DATA[(index / 8)..]);
-        rundata.read_bits((index % 8) as u8);
-        let rundata = rundata.read_bits(RUN_DATA_SIZE as u8).unwrap();
-        let kind = rundata % ((1 << BPP) + 1);
-        let length = rundata / ((1 << BPP) + 1);
+        let (kind, length) = read_run(stream, runs, prev_kind);
+        prev_kind = kind;
 
-        if kind == 1 << BPP {
-            i += length;
-        } else {
-            for _ in 0..length {
-                let (dx, dy) = get_xy(i, order, w, h);
-                set(x + dx, y + dy, kind as u8);
-                i += 1;
+        for _ in 0..length {
+            let (dx, dy) = next_xy(&mut p, order, w, h);
+            if kind != 1 << BPP {
+                match flags & XOR_FLAG != 0 {
+                    true => xor(x + dx, y + dy, kind as u8),
+                    false => set(x + dx, y + dy, kind as u8),
+                }
             }
         }
+        i += length;
     }
 }
 
-fn huffman_index(stream: &mut BitStream, tree: &[u8]) -> usize {
-    let mut tree_stream = BitStream::new(tree);
-    let mut value = 0;
-    while !tree_stream.read_one().unwrap() {
-        if stream.read_one().unwrap() {
-            value += count(&mut tree_stream);
-        }
-    }
-    value
-}
+fn read_run(stream: &mut BitStream, runs: &mut Runs, prev_kind: u32) -> (u32, u32) {
+    match RUN_CODER {
+        RunCoder::Huffman => match RUN_LAYOUT {
+            RunLayout::Joint => {
+                let index = huffman_index(|| stream.read_one().unwrap(), &RUN_LENGTH_COUNTS)
+                    * RUN_DATA_SIZE as usize;
+                let mut rundata = BitStream::new(&RUNS_DATA[(index / 8)..]);
+                rundata.read_bits((index % 8) as u8);
+                let rundata = rundata.read_bits(RUN_DATA_SIZE as u8).unwrap();
+                (rundata % KINDS as u32, rundata / KINDS as u32)
+            }
+            RunLayout::Split => {
+                let table = runs.table;
+                let kind = RUN_KINDS[table]
+                    [huffman_index(|| stream.read_one().unwrap(), &RUN_KIND_COUNTS[table])]
+                    as usize;
+                let buckets = &RUN_BUCKET_COUNTS[table][kind];
+                let bucket = RUN_BUCKETS[table][kind]
+                    [huffman_index(|| stream.read_one().unwrap(), buckets)]
+                    as u32;
+                (kind as u32, bucket_length(stream, bucket))
+            }
+        },
+        RunCoder::Arithmetic => {
+            let Runs {
+                decoder, contexts, ..
+            } = runs;
+            let tree = &mut contexts.kinds[prev_kind as usize];
+            let mut node = 1;
+            while node < 1 << KIND_BITS {
+                node = node * 2 + decoder.read_bit(&mut tree[node]) as usize;
+            }
+            let kind = node - (1 << KIND_BITS);
 
-fn count(tree: &mut BitStream) -> usize {
-    if tree.read_one().unwrap() {
-        1
-    } else {
-        count(tree) + count(tree)
+            let mut exponent = 0;
+            while decoder.read_bit(&mut contexts.exponents[kind][exponent]) {
+                exponent += 1;
+            }
+            let mut length = 1;
+            if exponent > 0 {
+                let top = decoder.read_bit(&mut contexts.mantissas[kind][exponent]) as u32;
+                length = (2 | top) << (exponent - 1) | decoder.read_direct(exponent as u32 - 1);
+            }
+            (kind as u32, length)
+        }
+        RunCoder::Rans => {
+            #[allow(clippy::unnecessary_cast)] // RansRun is u16 when every run fits
+            let rundata = RANS_RUNS[runs.rans.read(RANS_CUMULATIVE)] as u32;
+            (rundata % KINDS as u32, rundata / KINDS as u32)
+        }
     }
 }
 
@@ -142,6 +432,31 @@ fn set(x: u32, y: u32, v: u8) {
     }
 }
 
+fn get(x: u32, y: u32) -> u8 {
+    let (i, s) = locate(x * PIXEL_SIZE, y * PIXEL_SIZE);
+    unsafe { (*wasm4::FRAMEBUFFER)[i] >> s & 0b11 }
+}
+
+/// Fills a rect with the region of the screen `(dx, dy)` away, going in the direction
+/// that reads every source pixel before it is overwritten.
+fn copy_shifted(x: u32, y: u32, w: u32, h: u32, dx: i32, dy: i32) {
+    for row in 0..h {
+        let row = match dy < 0 {
+            true => h - 1 - row,
+            false => row,
+        };
+        for column in 0..w {
+            let column = match dy == 0 && dx < 0 {
+                true => w - 1 - column,
+                false => column,
+            };
+            let (tx, ty) = (x + column, y + row);
+            let source = get(tx.wrapping_add_signed(dx), ty.wrapping_add_signed(dy));
+            set(tx, ty, source);
+        }
+    }
+}
+
 fn xor(x: u32, y: u32, v: u8) {
     unsafe {
         for x in x * PIXEL_SIZE..(x + 1) * PIXEL_SIZE {
@@ -162,25 +477,6 @@ fn locate(x: u32, y: u32) -> (usize, u32) {
     (pixel_byte as usize, pixel_shift)
 }
 
-fn get_xy(i: u32, order: u32, w: u32, h: u32) -> (u32, u32) {
-    if order & 1 == 1 {
-        let (y, x) = get_xy(i, order & !1, h, w);
-        return (x, y);
-    }
-    match order {
-        0 => (i % w, i / w),
-        2 => {
-            let y = i / w;
-            let x = i % w;
-            match y % 2 != 0 {
-                false => (x, y),
-                true => (w - x - 1, y),
-            }
-        }
-        _ => unreachable!(),
-    }
-}
-
 #[panic_handler]
 fn panic_handler(_: &core::panic::PanicInfo) -> ! {
     loop {}
@@ -0,0 +1,102 @@
+// Pixel traversal orders, shared with the build script.
+
+/// Position of the next pixel of a rect in `order`, skipping the points of the
+/// order's curve that fall outside the rect.
+pub fn next_xy(p: &mut u32, order: u32, w: u32, h: u32) -> (u32, u32) {
+    loop {
+        let (x, y) = get_xy(*p, order, w, h);
+        *p += 1;
+        if x < w && y < h {
+            return (x, y);
+        }
+    }
+}
+
+/// Point `i` of `order`'s curve over a `w` by `h` rect. Odd orders are the order
+/// before them transposed.
+pub fn get_xy(i: u32, order: u32, w: u32, h: u32) -> (u32, u32) {
+    if order & 1 == 1 {
+        let (y, x) = get_xy(i, order & !1, h, w);
+        return (x, y);
+    }
+    match order {
+        0 => (i % w, i / w),
+        2 => {
+            let y = i / w;
+            let x = i % w;
+            match y % 2 == 1 {
+                false => (x, y),
+                true => (w - x - 1, y),
+            }
+        }
+        4 => tiled(i, w, h, hilbert_xy),
+        6 => tiled(i, w, h, morton_xy),
+        8 => zigzag_xy(i, w, h),
+        _ => unreachable!(),
+    }
+}
+
+/// Point `p` along a curve over squares of a power of two side, laid out along the
+/// rect's longer side. Points past the rect's edges are skipped by `next_xy`.
+fn tiled(p: u32, w: u32, h: u32, curve: fn(u32, u32) -> (u32, u32)) -> (u32, u32) {
+    let side = w.min(h).next_power_of_two();
+    let (x, y) = curve(p % (side * side), side);
+    let offset = p / (side * side) * side;
+    match w >= h {
+        true => (x + offset, y),
+        false => (x, y + offset),
+    }
+}
+
+fn hilbert_xy(d: u32, side: u32) -> (u32, u32) {
+    let (mut x, mut y, mut t) = (0, 0, d);
+    let mut s = 1;
+    while s < side {
+        let rx = (t / 2) & 1;
+        let ry = (t ^ rx) & 1;
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            (x, y) = (y, x);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x, y)
+}
+
+fn morton_xy(d: u32, _side: u32) -> (u32, u32) {
+    let (mut x, mut y) = (0, 0);
+    for bit in 0..16 {
+        x |= (d >> (2 * bit) & 1) << bit;
+        y |= (d >> (2 * bit + 1) & 1) << bit;
+    }
+    (x, y)
+}
+
+fn zigzag_xy(i: u32, w: u32, h: u32) -> (u32, u32) {
+    let m = w.min(h);
+    let corner = m * (m + 1) / 2;
+    let tail = m * (m - 1) / 2;
+    let (s, k) = if i < corner {
+        let s = ((8 * i + 1).isqrt() - 1) / 2;
+        (s, i - s * (s + 1) / 2)
+    } else if i < w * h - tail {
+        let j = i - corner;
+        (m + j / m, j % m)
+    } else {
+        let j = w * h - 1 - i;
+        let s = ((8 * j + 1).isqrt() - 1) / 2;
+        (w + h - 2 - s, s - (j - s * (s + 1) / 2))
+    };
+    let x_min = s.saturating_sub(h - 1);
+    let x_max = s.min(w - 1);
+    match s % 2 {
+        0 => (x_min + k, s - x_min - k),
+        _ => (x_max - k, s - x_max + k),
+    }
+}
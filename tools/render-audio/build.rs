@@ -0,0 +1,22 @@
+use std::env;
+use std::process::Command;
+
+#[path = "../../build/playback.rs"]
+mod playback;
+
+fn main() {
+    println!("cargo:rerun-if-changed=../../audio.py");
+    println!("cargo:rerun-if-changed=../../music.mid");
+    println!("cargo:rerun-if-changed=../../build/playback.rs");
+
+    // The same environment as the cart's build script, so the music has the same timing.
+    assert!(Command::new("./audio.py")
+        .current_dir("../..")
+        .env("OUT_DIR", env::var("OUT_DIR").unwrap())
+        .env("TICK_RATE", playback::TICK_RATE.to_string())
+        .env("AV_OFFSET", playback::AUDIO_OFFSET.to_string())
+        .env("LOOP_MUSIC", (playback::LOOP_MUSIC as u8).to_string())
+        .status()
+        .unwrap()
+        .success());
+}
@@ -0,0 +1,276 @@
+// Software model of the WASM-4 APU, following the reference implementation in
+// the WASM-4 runtime (runtimes/native/src/apu.c).
+
+pub const SAMPLE_RATE: u64 = 44100;
+pub const SAMPLES_PER_TICK: u64 = SAMPLE_RATE / 60;
+
+const MAX_VOLUME: i32 = 0x1333;
+const MAX_VOLUME_TRIANGLE: i32 = 0x2000;
+
+#[derive(Default)]
+struct Channel {
+    freq1: f32,
+    freq2: f32,
+    start_time: u64,
+    attack_time: u64,
+    decay_time: u64,
+    sustain_time: u64,
+    release_time: u64,
+    sustain_volume: i32,
+    peak_volume: i32,
+    phase: f32,
+    pan: u32,
+    duty_cycle: f32,
+    seed: u16,
+    last_random: i32,
+}
+
+pub struct Apu {
+    channels: [Channel; 4],
+    time: u64,
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        let mut apu = Apu {
+            channels: Default::default(),
+            time: 0,
+        };
+        apu.channels[3].seed = 1;
+        apu
+    }
+
+    pub fn tone(&mut self, frequency: u32, duration: u32, volume: u32, flags: u32) {
+        let freq1 = frequency & 0xFFFF;
+        let freq2 = frequency >> 16 & 0xFFFF;
+        let sustain = duration & 0xFF;
+        let release = duration >> 8 & 0xFF;
+        let decay = duration >> 16 & 0xFF;
+        let attack = duration >> 24 & 0xFF;
+        let sustain_volume = (volume & 0xFF).min(100) as i32;
+        let peak_volume = (volume >> 8 & 0xFF).min(100) as i32;
+        let channel_idx = (flags & 0x03) as usize;
+        let mode = flags >> 2 & 0x3;
+        let pan = flags >> 4 & 0x3;
+
+        let time = self.time;
+        let channel = &mut self.channels[channel_idx];
+
+        // Restart the phase if this channel wasn't already playing
+        if time > channel.release_time {
+            channel.phase = if channel_idx == 2 { 0.25 } else { 0.0 };
+        }
+
+        channel.freq1 = freq1 as f32;
+        channel.freq2 = freq2 as f32;
+        channel.start_time = time;
+        channel.attack_time = channel.start_time + SAMPLE_RATE * attack as u64 / 60;
+        channel.decay_time = channel.attack_time + SAMPLE_RATE * decay as u64 / 60;
+        channel.sustain_time = channel.decay_time + SAMPLE_RATE * sustain as u64 / 60;
+        channel.release_time = channel.sustain_time + SAMPLE_RATE * release as u64 / 60;
+        let max_volume = match channel_idx {
+            2 => MAX_VOLUME_TRIANGLE,
+            _ => MAX_VOLUME,
+        };
+        channel.sustain_volume = max_volume * sustain_volume / 100;
+        channel.peak_volume = match peak_volume {
+            0 => max_volume,
+            v => max_volume * v / 100,
+        };
+        channel.pan = pan;
+
+        match channel_idx {
+            0 | 1 => {
+                channel.duty_cycle = match mode {
+                    0 => 0.125,
+                    2 => 0.5,
+                    _ => 0.25,
+                }
+            }
+            // For the triangle channel, prevent popping on hard stops
+            2 if release == 0 => channel.release_time += SAMPLE_RATE / 1000,
+            _ => {}
+        }
+    }
+
+    pub fn write_samples(&mut self, output: &mut Vec<(i16, i16)>, frames: u64) {
+        for _ in 0..frames {
+            let mut mix_left = 0i16;
+            let mut mix_right = 0i16;
+            for (channel_idx, channel) in self.channels.iter_mut().enumerate() {
+                if self.time >= channel.release_time {
+                    continue;
+                }
+                let freq = channel.frequency(self.time);
+                let volume = channel.volume(self.time);
+                let sample = match channel_idx {
+                    3 => {
+                        channel.phase += freq * freq / 1_000_000.0;
+                        while channel.phase > 0.0 {
+                            channel.phase -= 1.0;
+                            channel.seed ^= channel.seed >> 7;
+                            channel.seed ^= channel.seed << 9;
+                            channel.seed ^= channel.seed >> 13;
+                            channel.last_random = 2 * (channel.seed & 1) as i32 - 1;
+                        }
+                        volume * channel.last_random
+                    }
+                    _ => {
+                        let phase_inc = freq / SAMPLE_RATE as f32;
+                        channel.phase += phase_inc;
+                        if channel.phase >= 1.0 {
+                            channel.phase -= 1.0;
+                        }
+                        if channel_idx == 2 {
+                            let t = 2.0 * (2.0 * channel.phase - 1.0).abs() - 1.0;
+                            (volume as f32 * t) as i32
+                        } else {
+                            let duty = channel.duty_cycle;
+                            let (duty_phase, duty_phase_inc, multiplier) = match channel.phase {
+                                p if p < duty => (p / duty, phase_inc / duty, volume),
+                                p => ((p - duty) / (1.0 - duty), phase_inc / (1.0 - duty), -volume),
+                            };
+                            (multiplier as f32 * polyblep(duty_phase, duty_phase_inc)) as i32
+                        }
+                    }
+                } as i16;
+                if channel.pan != 1 {
+                    mix_right = mix_right.wrapping_add(sample);
+                }
+                if channel.pan != 2 {
+                    mix_left = mix_left.wrapping_add(sample);
+                }
+            }
+            output.push((mix_left, mix_right));
+            self.time += 1;
+        }
+    }
+}
+
+impl Channel {
+    fn frequency(&self, time: u64) -> f32 {
+        if self.freq2 > 0.0 {
+            ramp(
+                self.freq1 as i32,
+                self.freq2 as i32,
+                self.start_time,
+                self.release_time,
+                time,
+            ) as f32
+        } else {
+            self.freq1
+        }
+    }
+
+    fn volume(&self, time: u64) -> i32 {
+        if time >= self.sustain_time && self.release_time > self.sustain_time {
+            ramp(
+                self.sustain_volume,
+                0,
+                self.sustain_time,
+                self.release_time,
+                time,
+            )
+        } else if time >= self.decay_time {
+            self.sustain_volume
+        } else if time >= self.attack_time {
+            ramp(
+                self.peak_volume,
+                self.sustain_volume,
+                self.attack_time,
+                self.decay_time,
+                time,
+            )
+        } else {
+            ramp(0, self.peak_volume, self.start_time, self.attack_time, time)
+        }
+    }
+}
+
+fn ramp(value1: i32, value2: i32, time1: u64, time2: u64, time: u64) -> i32 {
+    if time >= time2 {
+        return value2;
+    }
+    let t = (time - time1) as f32 / (time2 - time1) as f32;
+    (value1 as f32 + t * (value2 - value1) as f32) as i32
+}
+
+fn polyblep(phase: f32, phase_inc: f32) -> f32 {
+    if phase < phase_inc {
+        let t = phase / phase_inc;
+        t + t - t * t
+    } else if phase > 1.0 - phase_inc {
+        let t = (phase - (1.0 - phase_inc)) / phase_inc;
+        1.0 - (t + t - t * t)
+    } else {
+        1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Flags of a tone on the first pulse channel, in both ears, before the mode.
+    const PULSE_ONE: u32 = 0;
+
+    fn play(frequency: u32, duration: u32, volume: u32, flags: u32, samples: u64) -> Vec<i16> {
+        let mut apu = Apu::new();
+        apu.tone(frequency, duration, volume, flags);
+        let mut output = vec![];
+        apu.write_samples(&mut output, samples);
+        output.into_iter().map(|(left, _)| left).collect()
+    }
+
+    #[test]
+    fn duty_cycle() {
+        // 441 Hz is a period of 100 samples, and full volume is MAX_VOLUME.
+        for (mode, high) in [(0, 12..=13), (1, 24..=25), (2, 49..=50), (3, 24..=25)] {
+            let samples = play(441, 60, 100, PULSE_ONE | mode << 2, 300);
+            let period = &samples[100..200];
+            let count = period.iter().filter(|&&sample| sample > 0).count();
+            assert!(high.contains(&count), "mode {mode}: {count} high samples");
+            // Away from the edges, where polyBLEP smooths the step, the wave is flat.
+            assert_eq!(period[4], 0x1333);
+            assert_eq!(period[75], -0x1333);
+        }
+    }
+
+    #[test]
+    fn envelope() {
+        // At 1 Hz a 50% pulse is high for the first half second, so the samples
+        // follow the envelope: 10 ticks of attack to full volume, 5 of decay to
+        // half, 5 of sustain and 5 of release.
+        let samples = play(
+            1,
+            10 << 24 | 5 << 16 | 5 << 8 | 5,
+            100 << 8 | 50,
+            PULSE_ONE | 2 << 2,
+            20000,
+        );
+        let tick = SAMPLES_PER_TICK as usize;
+        assert_eq!(samples[5 * tick], 2457);
+        assert_eq!(samples[10 * tick], 0x1333);
+        assert_eq!(samples[15 * tick], 2457);
+        assert_eq!(samples[18 * tick], 2457);
+        assert_eq!(samples[20 * tick + tick * 5 / 2], 1228);
+        assert_eq!(samples[25 * tick], 0);
+    }
+
+    #[test]
+    fn slide() {
+        // A second-long slide from 441 Hz to 882 Hz averages 661.5 Hz.
+        let samples = play(882 << 16 | 441, 60, 100, PULSE_ONE | 2 << 2, SAMPLE_RATE);
+        // polyBLEP can round a sample at the edge to 0, so those are skipped.
+        let rising = |samples: &[i16]| {
+            let signs: Vec<_> = samples.iter().filter(|&&sample| sample != 0).collect();
+            signs.windows(2).filter(|w| *w[0] < 0 && *w[1] > 0).count()
+        };
+        let count = rising(&samples);
+        assert!((660..=662).contains(&count), "{count} periods");
+        // Its first and last tenths of a second are close to either end.
+        let tenth = SAMPLE_RATE as usize / 10;
+        assert!((45..=47).contains(&rising(&samples[..tenth])));
+        assert!((84..=86).contains(&rising(&samples[samples.len() - tenth..])));
+    }
+}
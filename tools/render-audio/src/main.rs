@@ -0,0 +1,214 @@
+use std::cell::RefCell;
+use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::apu::{Apu, SAMPLES_PER_TICK, SAMPLE_RATE};
+
+// The cart's sound effects aren't played by the renderer.
+#[allow(dead_code)]
+#[path = "../../../src/audio.rs"]
+mod audio;
+#[allow(dead_code)]
+#[path = "../../../src/bitstream.rs"]
+mod bitstream;
+#[allow(dead_code)]
+#[path = "../../../build/playback.rs"]
+mod playback;
+
+mod apu;
+
+// Stand-in for the cart's wasm4 module, routing tones into the software APU.
+mod wasm4 {
+    pub fn tone(frequency: u32, duration: u32, volume: u32, flags: u32) {
+        #[cfg(test)]
+        crate::tests::TONES.with(|tones| {
+            tones
+                .borrow_mut()
+                .push((frequency, duration, volume, flags))
+        });
+        crate::APU.with(|apu| apu.borrow_mut().tone(frequency, duration, volume, flags));
+    }
+}
+
+thread_local! {
+    static APU: RefCell<Apu> = RefCell::new(Apu::new());
+}
+
+const DEFAULT_SECONDS: u64 = 240;
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let path = args.next().unwrap_or_else(|| "music.wav".to_owned());
+    let seconds = args
+        .next()
+        .map(|s| s.parse().expect("length must be a number of seconds"))
+        .unwrap_or(DEFAULT_SECONDS);
+
+    let samples = render(seconds);
+    write_wav(BufWriter::new(File::create(&path).unwrap()), &samples).unwrap();
+    println!(
+        "{path}: {} samples, hash {:016x}",
+        samples.len(),
+        hash(&samples)
+    );
+}
+
+/// Plays the music for `seconds` and returns the samples the APU puts out.
+fn render(seconds: u64) -> Vec<(i16, i16)> {
+    let mut program = audio::Program::new();
+    let mut samples = vec![];
+    for _ in 0..seconds * playback::TICK_RATE as u64 {
+        program.update();
+        APU.with(|apu| {
+            apu.borrow_mut()
+                .write_samples(&mut samples, SAMPLES_PER_TICK)
+        });
+    }
+    samples
+}
+
+/// FNV-1a over the sample data, for comparing renders across changes.
+fn hash(samples: &[(i16, i16)]) -> u64 {
+    let mut hash = 0xCBF29CE484222325u64;
+    for &(l, r) in samples {
+        for byte in l.to_le_bytes().into_iter().chain(r.to_le_bytes()) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001B3);
+        }
+    }
+    hash
+}
+
+fn write_wav(mut to: impl Write, samples: &[(i16, i16)]) -> std::io::Result<()> {
+    let data_size = samples.len() as u32 * 4;
+    to.write_all(b"RIFF")?;
+    to.write_all(&(36 + data_size).to_le_bytes())?;
+    to.write_all(b"WAVEfmt ")?;
+    to.write_all(&16u32.to_le_bytes())?;
+    to.write_all(&1u16.to_le_bytes())?; // PCM
+    to.write_all(&2u16.to_le_bytes())?; // stereo
+    to.write_all(&(SAMPLE_RATE as u32).to_le_bytes())?;
+    to.write_all(&(SAMPLE_RATE as u32 * 4).to_le_bytes())?;
+    to.write_all(&4u16.to_le_bytes())?;
+    to.write_all(&16u16.to_le_bytes())?;
+    to.write_all(b"data")?;
+    to.write_all(&data_size.to_le_bytes())?;
+    for &(l, r) in samples {
+        to.write_all(&l.to_le_bytes())?;
+        to.write_all(&r.to_le_bytes())?;
+    }
+    to.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::{Channel, Program, Tone};
+
+    thread_local! {
+        /// Arguments of every call to `tone`.
+        pub static TONES: RefCell<Vec<(u32, u32, u32, u32)>> = const { RefCell::new(vec![]) };
+    }
+
+    const EFFECT: Tone = Tone {
+        start_freq: 1000,
+        end_freq: 0,
+        attack: 0,
+        decay: 0,
+        sustain: 5,
+        release: 3,
+        peak: 50,
+        volume: 50,
+        channel: Channel::PulseOne,
+        mode: 0,
+    };
+    const EFFECT_TICKS: usize = 8;
+
+    /// The tone started on the first pulse channel on each tick, with `EFFECT` played
+    /// before the tick `effect`.
+    fn pulse_one_tones(ticks: usize, effect: Option<usize>) -> Vec<Option<(u32, u32, u32, u32)>> {
+        let mut program = Program::new();
+        (0..ticks)
+            .map(|tick| {
+                TONES.with(|tones| tones.borrow_mut().clear());
+                if effect == Some(tick) {
+                    assert!(program.play_effect(EFFECT, 0));
+                }
+                program.update();
+                TONES.with(|tones| {
+                    let tones = tones.borrow();
+                    let mut pulse_one = tones.iter().filter(|&&(.., flags)| flags & 3 == 0);
+                    let tone = pulse_one.next().copied();
+                    assert_eq!(pulse_one.next(), None);
+                    tone
+                })
+            })
+            .collect()
+    }
+
+    /// Checks that the music is the same with `EFFECT` played at `effect` as without,
+    /// but for the effect and the rest of the note sounding when it ends.
+    fn check_effect(music: &[Option<(u32, u32, u32, u32)>], effect: usize) {
+        let end = effect + EFFECT_TICKS;
+        let with_effect = pulse_one_tones(music.len(), Some(effect));
+
+        assert_eq!(with_effect[..effect], music[..effect]);
+        assert_eq!(with_effect[effect].unwrap().0, EFFECT.start_freq as u32);
+        assert!(with_effect[effect + 1..end].iter().all(Option::is_none));
+        let (start, (frequency, duration, volume, flags)) = (0..=end)
+            .rev()
+            .find_map(|tick| Some((tick, music[tick]?)))
+            .unwrap();
+        let left = (duration & 0xFF) as i32 - (end - start) as i32;
+        assert_eq!(
+            with_effect[end],
+            (left > 0).then_some((frequency, duration & !0xFF | left as u32, volume, flags))
+        );
+        assert_eq!(with_effect[end + 1..], music[end + 1..]);
+    }
+
+    #[test]
+    fn effect_returns_channel() {
+        let music = pulse_one_tones(60 * playback::TICK_RATE as usize, None);
+        let starts: Vec<_> = (0..music.len())
+            .filter(|&tick| music[tick].is_some())
+            .collect();
+        // An effect cutting off a note that outlasts it
+        let long = starts
+            .iter()
+            .find(|&&tick| music[tick].unwrap().1 & 0xFF > EFFECT_TICKS as u32 + 2)
+            .unwrap();
+        check_effect(&music, long + 1);
+        // An effect during which the next note comes due
+        let next = starts
+            .windows(2)
+            .find(|ticks| ticks[1] - ticks[0] > 2)
+            .unwrap()[1];
+        check_effect(&music, next - 2);
+    }
+
+    /// The hash `render-audio` prints for the default length. A change to the music
+    /// player, the converter or `music.mid` that is meant to change the audio
+    /// updates it.
+    const MUSIC_HASH: u64 = 0xef77bfc70b4512b9;
+
+    #[test]
+    fn music_hash() {
+        let samples = render(DEFAULT_SECONDS);
+        assert_eq!(samples.len() as u64, DEFAULT_SECONDS * SAMPLE_RATE);
+        assert_eq!(hash(&samples), MUSIC_HASH);
+    }
+
+    #[test]
+    fn effect_priority() {
+        let mut program = Program::new();
+        assert!(program.play_effect(EFFECT, 1));
+        assert!(!program.play_effect(EFFECT, 0));
+        assert!(program.play_effect(EFFECT, 1));
+        for _ in 0..EFFECT_TICKS {
+            program.update();
+        }
+        assert!(program.play_effect(EFFECT, 0));
+    }
+}